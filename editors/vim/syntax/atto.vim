@@ -10,11 +10,20 @@ endif
 " keywords
 syn keyword attoKeyword     fn nextgroup=attoFnName skipwhite
 syn keyword attoKeyword     is
+syn keyword attoKeyword     do
 syn keyword attoCond        if
 syn keyword attoBoolean		true false
 syn keyword attoBuiltIn     __head __tail __fuse __pair
-syn keyword attoBuiltIn     __litr __str __words __input __print
+syn keyword attoBuiltIn     __litr __str __words __input __print __env __write_file
+syn keyword attoBuiltIn     __chars __from_chars __to_num
+syn keyword attoBuiltIn     __replace
+syn keyword attoBuiltIn     __trim
+syn keyword attoBuiltIn     __starts_with __contains
+syn keyword attoBuiltIn     __upper __lower
+syn keyword attoBuiltIn     __nth __split __join __take __drop __repr __assert
 syn keyword attoBuiltIn     __eq __add __neg __mul __div __rem
+syn keyword attoBuiltIn     __idiv __mod __abs __sign __pi __e __random __time
+syn keyword attoBuiltIn     __band __bor __bxor __shl __shr __bnot
 syn keyword attoBuiltIn     __less __lesseq
 
 " matches
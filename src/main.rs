@@ -1,9 +1,15 @@
 use std::{
     slice,
-    collections::HashMap,
+    collections::{HashMap, HashSet, BTreeMap},
     io::{self, prelude::*},
     env,
     fs,
+    process,
+    fmt,
+    cmp::Ordering,
+    hash::{Hash, Hasher},
+    cell::{Cell, RefCell},
+    time,
 };
 use rustyline::Editor;
 
@@ -13,9 +19,36 @@ enum Error {
     ExpectedToken,
     Unexpected(Token),
     CannotFind(String),
+    MainArity(usize),
+    IncorrectArity(String, usize, usize),
+    Redefined(String),
+    AssertFailed(String),
+    CannotWrite(String, String),
+    OutOfFuel,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Expected(t) => write!(f, "Expected '{}'", t),
+            Error::ExpectedToken => write!(f, "Unexpected end of input"),
+            Error::Unexpected(t) => write!(f, "Unexpected '{}'", t),
+            Error::CannotFind(name) => write!(f, "Cannot find function '{}'", name),
+            Error::IncorrectArity(name, expected, got) => write!(
+                f,
+                "Function '{}' expects {} arguments, found {}",
+                name, expected, got,
+            ),
+            Error::Redefined(name) => write!(f, "Function '{}' is defined more than once", name),
+            Error::MainArity(n) => write!(f, "Function 'main' must take no arguments, but takes {}", n),
+            Error::AssertFailed(msg) => write!(f, "Assertion failed: {}", msg),
+            Error::CannotWrite(fname, err) => write!(f, "Could not write to '{}': {}", fname, err),
+            Error::OutOfFuel => write!(f, "Evaluation took too many steps"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 enum Value {
     Num(f64),
     Str(String),
@@ -44,46 +77,233 @@ impl Value {
         }
     }
 
-    pub fn into_string(self) -> String {
+    fn kind_order(&self) -> u8 {
         match self {
-            Value::Num(x) => format!("{}", x),
-            Value::Str(s) => s,
-            Value::Bool(b) => format!("{}", b),
-            Value::List(l) => {
-                let mut s = String::from("[");
-                for i in 0..l.len() {
-                    if i != 0 {
-                        s += ", ";
-                    }
-                    s += &format!("{}", l[i].clone().into_string());
-                }
-                s += "]";
-                s
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Num(_) => 2,
+            Value::Str(_) => 3,
+            Value::List(_) => 4,
+        }
+    }
+
+    /// A total ordering over all values, consistent with `==`.
+    /// Values of different kinds are ordered null < bool < number < string < list, and NaN is greater
+    /// than every other number.
+    pub fn total_cmp(&self, other: &Self) -> Ordering {
+        // Lists are compared using an explicit stack rather than recursion so that deeply nested lists can't
+        // overflow the native stack
+        let mut stack = vec![(slice::from_ref(self).iter(), slice::from_ref(other).iter())];
+        while let Some((xs, ys)) = stack.last_mut() {
+            match (xs.next(), ys.next()) {
+                (None, None) => { stack.pop(); },
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+                (Some(Value::List(x)), Some(Value::List(y))) => stack.push((x.iter(), y.iter())),
+                (Some(x), Some(y)) => match x.cmp_shallow(y) {
+                    Ordering::Equal => {},
+                    ord => return ord,
+                },
+            }
+        }
+        Ordering::Equal
+    }
+
+    fn cmp_shallow(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Bool(x), Value::Bool(y)) => x.cmp(y),
+            (Value::Num(x), Value::Num(y)) => match (x.is_nan(), y.is_nan()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => x.partial_cmp(y).unwrap(),
             },
-            Value::Null => "null".to_string(),
+            (Value::Str(x), Value::Str(y)) => x.cmp(y),
+            (x, y) => x.kind_order().cmp(&y.kind_order()),
+        }
+    }
+
+    /// Iterate over the items of a list, yielding nothing for any other value
+    pub fn iter(&self) -> slice::Iter<'_, Value> {
+        match self {
+            Value::List(items) => items.iter(),
+            _ => [].iter(),
         }
     }
 }
 
-#[derive(Clone, Debug)]
+// NaN is considered equal to itself so that values can always be compared structurally
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.total_cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind_order().hash(state);
+        match self {
+            // Numbers that are equal must hash equally, so NaN and zero are normalised first
+            Value::Num(x) => if x.is_nan() {
+                f64::NAN.to_bits()
+            } else if *x == 0.0 {
+                0
+            } else {
+                x.to_bits()
+            }.hash(state),
+            Value::Str(s) => s.hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::List(l) => l.hash(state),
+            Value::Null => {},
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        enum Item<'a> {
+            Value(&'a Value),
+            Punct(&'static str),
+        }
+
+        // Rendered using an explicit stack rather than recursion so that deeply nested lists can't overflow the
+        // native stack
+        let mut stack = vec![Item::Value(self)];
+        while let Some(item) = stack.pop() {
+            match item {
+                Item::Punct(s) => write!(f, "{}", s)?,
+                Item::Value(Value::Num(x)) => write!(f, "{}", x)?,
+                Item::Value(Value::Str(s)) => write!(f, "{}", s)?,
+                Item::Value(Value::Bool(b)) => write!(f, "{}", b)?,
+                Item::Value(list @ Value::List(_)) => {
+                    write!(f, "[")?;
+                    stack.push(Item::Punct("]"));
+                    for (i, item) in list.iter().enumerate().rev() {
+                        stack.push(Item::Value(item));
+                        if i != 0 {
+                            stack.push(Item::Punct(", "));
+                        }
+                    }
+                },
+                Item::Value(Value::Null) => write!(f, "null")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 enum Token {
     Fn, Is,
 
-    If,   Head,  Tail,
-    Fuse, Pair,  Litr,
+    If,   Do,    Semi,
+    Head, Tail,
+    Fuse, Pair,  Litr, ToNum,
     Str,  Words, Input,
-    Print,
+    Chars, FromChars,
+    Replace,
+    Trim,
+    StartsWith, Contains,
+    Upper, Lower,
+    Print, Env, WriteFile, Nth,
+    Take, Drop,
+    Split, Join, Repr,
+    Assert,
 
-    Add,  Neg,
+    Add,  Neg, Abs, Sign,
+    Pi, E, Random, Time,
     Mul, Div, Rem,
+    IDiv, Mod,
+    BAnd, BOr, BXor, Shl, Shr, BNot,
     Eq,
     Less, LessEq,
 
+    LParen, RParen,
+
     Value(Value),
     Ident(String),
 }
 
-#[derive(Debug)]
+/// The spelling of every keyword and builtin
+const KEYWORDS: &[(&str, Token)] = &[
+    ("fn", Token::Fn),
+    ("is", Token::Is),
+    ("(", Token::LParen),
+    (")", Token::RParen),
+    ("if", Token::If),
+    ("do", Token::Do),
+    (";", Token::Semi),
+    ("__head", Token::Head),
+    ("__tail", Token::Tail),
+    ("__fuse", Token::Fuse),
+    ("__pair", Token::Pair),
+    ("__litr", Token::Litr),
+    ("__to_num", Token::ToNum),
+    ("__str", Token::Str),
+    ("__repr", Token::Repr),
+    ("__assert", Token::Assert),
+    ("__words", Token::Words),
+    ("__chars", Token::Chars),
+    ("__from_chars", Token::FromChars),
+    ("__upper", Token::Upper),
+    ("__lower", Token::Lower),
+    ("__starts_with", Token::StartsWith),
+    ("__contains", Token::Contains),
+    ("__trim", Token::Trim),
+    ("__replace", Token::Replace),
+    ("__input", Token::Input),
+    ("__env", Token::Env),
+    ("__write_file", Token::WriteFile),
+    ("__print", Token::Print),
+    ("__nth", Token::Nth),
+    ("__take", Token::Take),
+    ("__drop", Token::Drop),
+    ("__split", Token::Split),
+    ("__join", Token::Join),
+    ("__eq", Token::Eq),
+    ("__add", Token::Add),
+    ("__neg", Token::Neg),
+    ("__abs", Token::Abs),
+    ("__sign", Token::Sign),
+    ("__pi", Token::Pi),
+    ("__e", Token::E),
+    ("__random", Token::Random),
+    ("__time", Token::Time),
+    ("__mul", Token::Mul),
+    ("__div", Token::Div),
+    ("__rem", Token::Rem),
+    ("__idiv", Token::IDiv),
+    ("__mod", Token::Mod),
+    ("__band", Token::BAnd),
+    ("__bor", Token::BOr),
+    ("__bxor", Token::BXor),
+    ("__shl", Token::Shl),
+    ("__shr", Token::Shr),
+    ("__bnot", Token::BNot),
+    ("__less", Token::Less),
+    ("__lesseq", Token::LessEq),
+];
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Value(Value::Str(s)) => write!(f, "\"{}\"", s),
+            Token::Value(v) => write!(f, "{}", v),
+            Token::Ident(i) => write!(f, "{}", i),
+            token => {
+                let (name, _) = KEYWORDS
+                    .iter()
+                    .find(|(_, t)| t == token)
+                    .expect("Every other token is a keyword");
+                write!(f, "{}", name)
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 enum Expr {
     If(Box<Expr>, Box<Expr>, Box<Expr>),
     Head(Box<Expr>),
@@ -91,193 +311,1020 @@ enum Expr {
     Fuse(Box<Expr>, Box<Expr>),
     Pair(Box<Expr>, Box<Expr>),
     Litr(Box<Expr>),
+    ToNum(Box<Expr>),
     Str(Box<Expr>),
+    Repr(Box<Expr>),
+    Assert(Box<Expr>, Box<Expr>),
     Words(Box<Expr>),
+    Chars(Box<Expr>),
+    FromChars(Box<Expr>),
+    Upper(Box<Expr>),
+    Lower(Box<Expr>),
+    StartsWith(Box<Expr>, Box<Expr>),
+    Contains(Box<Expr>, Box<Expr>),
+    Trim(Box<Expr>),
+    Replace(Box<Expr>, Box<Expr>, Box<Expr>),
     Input(Box<Expr>),
+    Env(Box<Expr>),
+    WriteFile(Box<Expr>, Box<Expr>),
     Print(Box<Expr>),
+    Nth(Box<Expr>, Box<Expr>),
+    Take(Box<Expr>, Box<Expr>),
+    Drop(Box<Expr>, Box<Expr>),
+    Split(Box<Expr>, Box<Expr>),
+    Join(Box<Expr>, Box<Expr>),
 
     Eq(Box<Expr>, Box<Expr>),
     Add(Box<Expr>, Box<Expr>),
     Neg(Box<Expr>),
+    Abs(Box<Expr>),
+    Sign(Box<Expr>),
+    Pi,
+    E,
+    Random,
+    Time,
     Mul(Box<Expr>, Box<Expr>),
     Div(Box<Expr>, Box<Expr>),
     Rem(Box<Expr>, Box<Expr>),
+    IDiv(Box<Expr>, Box<Expr>),
+    Mod(Box<Expr>, Box<Expr>),
+    BAnd(Box<Expr>, Box<Expr>),
+    BOr(Box<Expr>, Box<Expr>),
+    BXor(Box<Expr>, Box<Expr>),
+    Shl(Box<Expr>, Box<Expr>),
+    Shr(Box<Expr>, Box<Expr>),
+    BNot(Box<Expr>),
     Less(Box<Expr>, Box<Expr>),
     LessEq(Box<Expr>, Box<Expr>),
 
     Value(Value),
     Call(String, Vec<Expr>),
     Local(usize),
+    // Evaluates each expression in turn, producing the value of the last
+    Seq(Vec<Expr>),
 }
 
-#[derive(Debug)]
+impl Expr {
+    /// The token for the builtin that this expression invokes, if any
+    fn token(&self) -> Option<Token> {
+        match self {
+            Expr::Head(..) => Some(Token::Head),
+            Expr::Tail(..) => Some(Token::Tail),
+            Expr::Litr(..) => Some(Token::Litr),
+            Expr::ToNum(..) => Some(Token::ToNum),
+            Expr::Str(..) => Some(Token::Str),
+            Expr::Repr(..) => Some(Token::Repr),
+            Expr::Assert(..) => Some(Token::Assert),
+            Expr::Words(..) => Some(Token::Words),
+            Expr::Chars(..) => Some(Token::Chars),
+            Expr::FromChars(..) => Some(Token::FromChars),
+            Expr::Upper(..) => Some(Token::Upper),
+            Expr::Lower(..) => Some(Token::Lower),
+            Expr::StartsWith(..) => Some(Token::StartsWith),
+            Expr::Contains(..) => Some(Token::Contains),
+            Expr::Trim(..) => Some(Token::Trim),
+            Expr::Replace(..) => Some(Token::Replace),
+            Expr::Input(..) => Some(Token::Input),
+            Expr::Env(..) => Some(Token::Env),
+            Expr::WriteFile(..) => Some(Token::WriteFile),
+            Expr::Print(..) => Some(Token::Print),
+            Expr::Neg(..) => Some(Token::Neg),
+            Expr::Abs(..) => Some(Token::Abs),
+            Expr::Sign(..) => Some(Token::Sign),
+            Expr::Pi => Some(Token::Pi),
+            Expr::E => Some(Token::E),
+            Expr::Random => Some(Token::Random),
+            Expr::Time => Some(Token::Time),
+            Expr::Fuse(..) => Some(Token::Fuse),
+            Expr::Pair(..) => Some(Token::Pair),
+            Expr::Nth(..) => Some(Token::Nth),
+            Expr::Take(..) => Some(Token::Take),
+            Expr::Drop(..) => Some(Token::Drop),
+            Expr::Split(..) => Some(Token::Split),
+            Expr::Join(..) => Some(Token::Join),
+            Expr::Eq(..) => Some(Token::Eq),
+            Expr::Add(..) => Some(Token::Add),
+            Expr::Mul(..) => Some(Token::Mul),
+            Expr::Div(..) => Some(Token::Div),
+            Expr::Rem(..) => Some(Token::Rem),
+            Expr::IDiv(..) => Some(Token::IDiv),
+            Expr::Mod(..) => Some(Token::Mod),
+            Expr::BAnd(..) => Some(Token::BAnd),
+            Expr::BOr(..) => Some(Token::BOr),
+            Expr::BXor(..) => Some(Token::BXor),
+            Expr::Shl(..) => Some(Token::Shl),
+            Expr::Shr(..) => Some(Token::Shr),
+            Expr::BNot(..) => Some(Token::BNot),
+            Expr::Less(..) => Some(Token::Less),
+            Expr::LessEq(..) => Some(Token::LessEq),
+            Expr::If(..) | Expr::Value(_) | Expr::Call(..) | Expr::Local(_) | Expr::Seq(_) => None,
+        }
+    }
+
+    fn visit_children(&self, mut f: impl FnMut(&Expr)) {
+        match self {
+            Expr::If(x, y, z) |
+            Expr::Replace(x, y, z) => {
+                f(x);
+                f(y);
+                f(z);
+            },
+            Expr::Head(x) |
+            Expr::Env(x) |
+            Expr::BNot(x) |
+            Expr::Trim(x) |
+            Expr::Upper(x) |
+            Expr::Lower(x) |
+            Expr::ToNum(x) |
+            Expr::FromChars(x) |
+            Expr::Chars(x) |
+            Expr::Sign(x) |
+            Expr::Abs(x) |
+            Expr::Tail(x) |
+            Expr::Litr(x) |
+            Expr::Str(x) |
+            Expr::Repr(x) |
+            Expr::Words(x) |
+            Expr::Input(x) |
+            Expr::Print(x) |
+            Expr::Neg(x) => f(x),
+            Expr::Fuse(x, y) |
+            Expr::WriteFile(x, y) |
+            Expr::BAnd(x, y) |
+            Expr::BOr(x, y) |
+            Expr::BXor(x, y) |
+            Expr::Shl(x, y) |
+            Expr::Shr(x, y) |
+            Expr::StartsWith(x, y) |
+            Expr::Contains(x, y) |
+            Expr::Assert(x, y) |
+            Expr::Pair(x, y) |
+            Expr::Nth(x, y) |
+            Expr::Take(x, y) |
+            Expr::Drop(x, y) |
+            Expr::Split(x, y) |
+            Expr::Join(x, y) |
+            Expr::Eq(x, y) |
+            Expr::Add(x, y) |
+            Expr::Mul(x, y) |
+            Expr::Div(x, y) |
+            Expr::Rem(x, y) |
+            Expr::IDiv(x, y) |
+            Expr::Mod(x, y) |
+            Expr::Less(x, y) |
+            Expr::LessEq(x, y) => {
+                f(x);
+                f(y);
+            },
+            Expr::Call(_, params) |
+            Expr::Seq(params) => params.iter().for_each(f),
+            Expr::Value(_) | Expr::Local(_) | Expr::Pi | Expr::E | Expr::Random | Expr::Time => {},
+        }
+    }
+
+    /// Whether evaluating this expression performs I/O, either directly or by calling one of the `impure` functions
+    fn is_impure(&self, impure: &HashSet<String>) -> bool {
+        let mut found = match self {
+            Expr::Input(_) | Expr::Print(_) | Expr::WriteFile(..) | Expr::Random | Expr::Time => true,
+            Expr::Call(name, _) => impure.contains(name),
+            _ => false,
+        };
+        self.visit_children(|child| found |= child.is_impure(impure));
+        found
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 struct Func {
     args: Vec<String>,
     expr: Expr,
 }
 
-fn print(msg: String) {
-    println!("{}", msg);
+/// State shared by the whole of a single evaluation
+/// An argument in a memoisation key. Numbers are compared by their exact bits, since `Value`'s own equality
+/// treats `0` and `-0` as equal even though functions like `str` can tell them apart.
+struct MemoArg(Value);
+
+impl MemoArg {
+    fn exact_eq(x: &Value, y: &Value) -> bool {
+        match (x, y) {
+            (Value::Num(x), Value::Num(y)) => x.to_bits() == y.to_bits(),
+            (Value::List(xs), Value::List(ys)) => xs.len() == ys.len() && xs
+                .iter()
+                .zip(ys)
+                .all(|(x, y)| Self::exact_eq(x, y)),
+            (x, y) => x == y,
+        }
+    }
+}
+
+impl PartialEq for MemoArg {
+    fn eq(&self, other: &Self) -> bool {
+        Self::exact_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for MemoArg {}
+
+// Arguments that are exactly equal are also equal as values, so hashing them as values is consistent
+impl Hash for MemoArg {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+struct Ctx<'o> {
+    out: Box<dyn Write + 'o>,
+    lazy: bool,
+    // Functions whose results may be memoised, and the results computed so far
+    pure: HashSet<String>,
+    memo: HashMap<(String, Vec<MemoArg>), Value>,
+    rng: u64,
+    // The number of expressions that may still be evaluated, if limited
+    fuel: Option<u64>,
+}
+
+impl<'o> Ctx<'o> {
+    fn new(out: Box<dyn Write + 'o>, lazy: bool, pure: HashSet<String>, rng: u64, fuel: Option<u64>) -> Self {
+        Self {
+            out,
+            lazy,
+            pure,
+            memo: HashMap::new(),
+            rng,
+            fuel,
+        }
+    }
+
+    /// Produce a pseudo-random number in the range [0, 1) (using SplitMix64)
+    fn random(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// An argument passed to a function, evaluated no more than once
+struct Arg<'a> {
+    expr: &'a Expr,
+    env: &'a [Arg<'a>],
+    val: RefCell<Option<Value>>,
+}
+
+fn force(arg: &Arg, funcs: &BTreeMap<String, Func>, ctx: &mut Ctx) -> Result<Value, Error> {
+    if let Some(val) = arg.val.borrow().as_ref() {
+        return Ok(val.clone());
+    }
+    let val = eval(arg.expr, funcs, arg.env, ctx)?;
+    *arg.val.borrow_mut() = Some(val.clone());
+    Ok(val)
+}
+
+fn print(msg: String, ctx: &mut Ctx) {
+    let _ = writeln!(ctx.out, "{}", msg);
 }
 
-fn input(msg: String) -> Value {
-    print!("{}", msg);
-    io::stdout().flush().unwrap();
+fn input(msg: String, ctx: &mut Ctx) -> Value {
+    let _ = write!(ctx.out, "{}", msg);
+    let _ = ctx.out.flush();
+
+    read_line(&mut io::stdin().lock())
+}
 
+fn read_line(reader: &mut impl BufRead) -> Value {
     let mut input = String::new();
-    io::stdin().read_line(&mut input).unwrap();
-    input = input.replace('\n', "");
-    Value::Str(input)
+    match reader.read_line(&mut input) {
+        // End of input is distinct from an empty line, and unreadable input (e.g: invalid UTF-8) is treated like it
+        Ok(0) | Err(_) => Value::Null,
+        Ok(_) => Value::Str(input.trim_end_matches(&['\n', '\r'][..]).to_string()),
+    }
 }
 
-fn eval(expr: &Expr, funcs: &HashMap<String, Func>, args: &Vec<Value>) -> Value {
+fn eval<'a>(expr: &'a Expr, funcs: &'a BTreeMap<String, Func>, args: &'a [Arg<'a>], ctx: &mut Ctx) -> Result<Value, Error> {
+    if let Some(fuel) = &mut ctx.fuel {
+        *fuel = fuel.checked_sub(1).ok_or(Error::OutOfFuel)?;
+    }
+
     match expr {
-        Expr::If(pred, good, bad) => if eval(&pred, funcs, args) == Value::Bool(true) {
-            eval(&good, funcs, args)
-        } else {
-            eval(&bad, funcs, args)
+        Expr::If(pred, good, bad) => eval_if(pred, good, bad, funcs, args, ctx),
+        Expr::Eq(x, y) => binary(x, y, funcs, args, ctx, builtins::eq),
+        Expr::Add(x, y) => binary(x, y, funcs, args, ctx, builtins::add),
+        Expr::Neg(x) => unary(x, funcs, args, ctx, builtins::neg),
+        Expr::Abs(x) => unary(x, funcs, args, ctx, builtins::abs),
+        Expr::Sign(x) => unary(x, funcs, args, ctx, builtins::sign),
+        Expr::Pi => Ok(Value::Num(std::f64::consts::PI)),
+        Expr::E => Ok(Value::Num(std::f64::consts::E)),
+        Expr::Random => Ok(Value::Num(ctx.random())),
+        Expr::Time => Ok(builtins::time()),
+        Expr::Mul(x, y) => binary(x, y, funcs, args, ctx, builtins::mul),
+        Expr::Div(x, y) => binary(x, y, funcs, args, ctx, builtins::div),
+        Expr::Rem(x, y) => binary(x, y, funcs, args, ctx, builtins::rem),
+        Expr::IDiv(x, y) => binary(x, y, funcs, args, ctx, builtins::idiv),
+        Expr::Mod(x, y) => binary(x, y, funcs, args, ctx, builtins::modulo),
+        Expr::BAnd(x, y) => binary(x, y, funcs, args, ctx, builtins::band),
+        Expr::BOr(x, y) => binary(x, y, funcs, args, ctx, builtins::bor),
+        Expr::BXor(x, y) => binary(x, y, funcs, args, ctx, builtins::bxor),
+        Expr::Shl(x, y) => binary(x, y, funcs, args, ctx, builtins::shl),
+        Expr::Shr(x, y) => binary(x, y, funcs, args, ctx, builtins::shr),
+        Expr::BNot(x) => unary(x, funcs, args, ctx, builtins::bnot),
+        Expr::Less(x, y) => binary(x, y, funcs, args, ctx, builtins::less),
+        Expr::LessEq(x, y) => binary(x, y, funcs, args, ctx, builtins::lesseq),
+        Expr::Head(list) => unary(list, funcs, args, ctx, builtins::head),
+        Expr::Tail(list) => unary(list, funcs, args, ctx, builtins::tail),
+        Expr::Fuse(x, y) => binary(x, y, funcs, args, ctx, builtins::fuse),
+        Expr::Pair(x, y) => binary(x, y, funcs, args, ctx, builtins::pair),
+        Expr::Nth(n, list) => binary(n, list, funcs, args, ctx, builtins::nth),
+        Expr::Take(n, list) => binary(n, list, funcs, args, ctx, builtins::take),
+        Expr::Drop(n, list) => binary(n, list, funcs, args, ctx, builtins::drop),
+        Expr::Call(name, params) => eval_call(name, params, funcs, args, ctx),
+        Expr::Split(sep, x) => binary(sep, x, funcs, args, ctx, builtins::split),
+        Expr::Join(sep, x) => binary(sep, x, funcs, args, ctx, builtins::join),
+        Expr::Words(x) => unary(x, funcs, args, ctx, builtins::words),
+        Expr::Chars(x) => unary(x, funcs, args, ctx, builtins::chars),
+        Expr::FromChars(x) => unary(x, funcs, args, ctx, builtins::from_chars),
+        Expr::Upper(x) => unary(x, funcs, args, ctx, builtins::upper),
+        Expr::Lower(x) => unary(x, funcs, args, ctx, builtins::lower),
+        Expr::StartsWith(x, prefix) => binary(x, prefix, funcs, args, ctx, builtins::starts_with),
+        Expr::Contains(x, needle) => binary(x, needle, funcs, args, ctx, builtins::contains),
+        Expr::Trim(x) => unary(x, funcs, args, ctx, builtins::trim),
+        Expr::Replace(x, from, to) => ternary(x, from, to, funcs, args, ctx, builtins::replace),
+        Expr::Litr(x) => unary(x, funcs, args, ctx, builtins::litr),
+        Expr::ToNum(x) => unary(x, funcs, args, ctx, builtins::to_num),
+        Expr::Input(x) => eval_input(x, funcs, args, ctx),
+        Expr::Env(x) => unary(x, funcs, args, ctx, builtins::env),
+        Expr::WriteFile(fname, x) => eval_write_file(fname, x, funcs, args, ctx),
+        Expr::Print(x) => eval_print(x, funcs, args, ctx),
+        Expr::Str(x) => unary(x, funcs, args, ctx, builtins::to_str),
+        Expr::Repr(x) => unary(x, funcs, args, ctx, builtins::repr),
+        Expr::Assert(x, msg) => eval_assert(x, msg, funcs, args, ctx),
+        Expr::Seq(exprs) => eval_seq(exprs, funcs, args, ctx),
+        Expr::Value(val) => Ok(val.clone()),
+        Expr::Local(idx) => match args.get(*idx) {
+            Some(arg) => force(arg, funcs, ctx),
+            None => Ok(Value::Null),
         },
-        Expr::Eq(x, y) => Value::Bool(eval(&x, funcs, args) == eval(&y, funcs, args)),
-        Expr::Add(x, y) => match (eval(&x, funcs, args), eval(&y, funcs, args)) {
+    }
+}
+
+/// Evaluate the operand of a builtin, then apply it
+///
+/// This and the other helpers below are kept out of line so that `eval`, which every recursive call passes through,
+/// keeps a small stack frame.
+#[inline(never)]
+fn unary<'a>(
+    x: &'a Expr,
+    funcs: &'a BTreeMap<String, Func>,
+    args: &'a [Arg<'a>],
+    ctx: &mut Ctx,
+    f: fn(Value) -> Value,
+) -> Result<Value, Error> {
+    Ok(f(eval(x, funcs, args, ctx)?))
+}
+
+/// Evaluate both operands of a builtin, left to right, then apply it
+#[inline(never)]
+fn binary<'a>(
+    x: &'a Expr,
+    y: &'a Expr,
+    funcs: &'a BTreeMap<String, Func>,
+    args: &'a [Arg<'a>],
+    ctx: &mut Ctx,
+    f: fn(Value, Value) -> Value,
+) -> Result<Value, Error> {
+    let x = eval(x, funcs, args, ctx)?;
+    let y = eval(y, funcs, args, ctx)?;
+    Ok(f(x, y))
+}
+
+#[inline(never)]
+fn eval_if<'a>(
+    pred: &'a Expr,
+    good: &'a Expr,
+    bad: &'a Expr,
+    funcs: &'a BTreeMap<String, Func>,
+    args: &'a [Arg<'a>],
+    ctx: &mut Ctx,
+) -> Result<Value, Error> {
+    if eval(pred, funcs, args, ctx)? == Value::Bool(true) {
+        eval(good, funcs, args, ctx)
+    } else {
+        eval(bad, funcs, args, ctx)
+    }
+}
+
+#[inline(never)]
+fn eval_call<'a>(
+    name: &'a String,
+    params: &'a [Expr],
+    funcs: &'a BTreeMap<String, Func>,
+    args: &'a [Arg<'a>],
+    ctx: &mut Ctx,
+) -> Result<Value, Error> {
+    let f = match funcs.get(name) {
+        Some(f) => f,
+        None => return Ok(Value::Null),
+    };
+    // A plain loop rather than an iterator chain, which would add to this function's stack frame in every recursive call
+    let mut call_args = Vec::with_capacity(params.len());
+    for p in params {
+        call_args.push(Arg {
+            expr: p,
+            env: args,
+            val: RefCell::new(if ctx.lazy { None } else { Some(eval(p, funcs, args, ctx)?) }),
+        });
+    }
+    if ctx.pure.contains(name) {
+        eval_memoised(name, f, &call_args, funcs, ctx)
+    } else {
+        eval(&f.expr, funcs, &call_args, ctx)
+    }
+}
+
+#[inline(never)]
+fn eval_memoised<'a>(
+    name: &'a str,
+    f: &'a Func,
+    params: &'a [Arg<'a>],
+    funcs: &'a BTreeMap<String, Func>,
+    ctx: &mut Ctx,
+) -> Result<Value, Error> {
+    let key = (name.to_string(), params.iter().map(|p| force(p, funcs, ctx).map(MemoArg)).collect::<Result<_, _>>()?);
+    if let Some(val) = ctx.memo.get(&key) {
+        return Ok(val.clone());
+    }
+    let val = eval(&f.expr, funcs, params, ctx)?;
+    ctx.memo.insert(key, val.clone());
+    Ok(val)
+}
+
+#[inline(never)]
+fn eval_input<'a>(x: &'a Expr, funcs: &'a BTreeMap<String, Func>, args: &'a [Arg<'a>], ctx: &mut Ctx) -> Result<Value, Error> {
+    let msg = eval(x, funcs, args, ctx)?.to_string();
+    Ok(input(msg, ctx))
+}
+
+#[inline(never)]
+fn eval_print<'a>(x: &'a Expr, funcs: &'a BTreeMap<String, Func>, args: &'a [Arg<'a>], ctx: &mut Ctx) -> Result<Value, Error> {
+    let val = eval(x, funcs, args, ctx)?;
+    print(val.to_string(), ctx);
+    Ok(val)
+}
+
+#[inline(never)]
+fn eval_assert<'a>(
+    x: &'a Expr,
+    msg: &'a Expr,
+    funcs: &'a BTreeMap<String, Func>,
+    args: &'a [Arg<'a>],
+    ctx: &mut Ctx,
+) -> Result<Value, Error> {
+    match eval(x, funcs, args, ctx)? {
+        Value::Bool(false) => Err(Error::AssertFailed(eval(msg, funcs, args, ctx)?.to_string())),
+        _ => Ok(Value::Null),
+    }
+}
+
+/// Evaluate all three operands of a builtin, left to right, then apply it
+#[inline(never)]
+fn ternary<'a>(
+    x: &'a Expr,
+    y: &'a Expr,
+    z: &'a Expr,
+    funcs: &'a BTreeMap<String, Func>,
+    args: &'a [Arg<'a>],
+    ctx: &mut Ctx,
+    f: fn(Value, Value, Value) -> Value,
+) -> Result<Value, Error> {
+    let x = eval(x, funcs, args, ctx)?;
+    let y = eval(y, funcs, args, ctx)?;
+    let z = eval(z, funcs, args, ctx)?;
+    Ok(f(x, y, z))
+}
+
+#[inline(never)]
+fn eval_write_file<'a>(fname: &'a Expr, x: &'a Expr, funcs: &'a BTreeMap<String, Func>, args: &'a [Arg<'a>], ctx: &mut Ctx) -> Result<Value, Error> {
+    let fname = eval(fname, funcs, args, ctx)?;
+    let x = eval(x, funcs, args, ctx)?;
+    if let Value::Str(fname) = fname {
+        fs::write(&fname, x.to_string()).map_err(|err| Error::CannotWrite(fname, err.to_string()))?;
+    }
+    Ok(Value::Null)
+}
+
+#[inline(never)]
+fn eval_seq<'a>(exprs: &'a [Expr], funcs: &'a BTreeMap<String, Func>, args: &'a [Arg<'a>], ctx: &mut Ctx) -> Result<Value, Error> {
+    let mut val = Value::Null;
+    for expr in exprs {
+        val = eval(expr, funcs, args, ctx)?;
+    }
+    Ok(val)
+}
+
+/// The behaviour of each builtin, given its already-evaluated operands
+mod builtins {
+    use super::*;
+
+    pub fn eq(x: Value, y: Value) -> Value {
+        Value::Bool(x == y)
+    }
+
+    pub fn add(x: Value, y: Value) -> Value {
+        match (x, y) {
             (Value::Num(x), Value::Num(y)) => Value::Num(x + y),
             (Value::Str(x), Value::Str(y)) => Value::Str(x + &y),
             _ => Value::Null,
-        },
-        Expr::Neg(x) => match eval(&x, funcs, args) {
+        }
+    }
+
+    pub fn neg(x: Value) -> Value {
+        match x {
             Value::Num(x) => Value::Num(-x),
             _ => Value::Null,
-        },
-        Expr::Mul(x, y) => match (eval(&x, funcs, args), eval(&y, funcs, args)) {
+        }
+    }
+
+    pub fn mul(x: Value, y: Value) -> Value {
+        match (x, y) {
             (Value::Num(x), Value::Num(y)) => Value::Num(x * y),
             _ => Value::Null,
-        },
-        Expr::Div(x, y) => match (eval(&x, funcs, args), eval(&y, funcs, args)) {
+        }
+    }
+
+    pub fn div(x: Value, y: Value) -> Value {
+        match (x, y) {
+            // Division by zero has no sensible result
+            (Value::Num(_), Value::Num(0.0)) => Value::Null,
             (Value::Num(x), Value::Num(y)) => Value::Num(x / y),
             _ => Value::Null,
-        },
-        Expr::Rem(x, y) => match (eval(&x, funcs, args), eval(&y, funcs, args)) {
+        }
+    }
+
+    pub fn rem(x: Value, y: Value) -> Value {
+        match (x, y) {
+            (Value::Num(_), Value::Num(0.0)) => Value::Null,
             (Value::Num(x), Value::Num(y)) => Value::Num(x % y),
             _ => Value::Null,
-        },
-        Expr::Less(x, y) => match (eval(&x, funcs, args), eval(&y, funcs, args)) {
-            (Value::Num(x), Value::Num(y)) => Value::Bool(x < y),
-            (Value::Str(x), Value::Str(y)) => Value::Bool(x < y),
+        }
+    }
+
+    pub fn less(x: Value, y: Value) -> Value {
+        match (x, y) {
+            (x @ Value::Num(_), y @ Value::Num(_)) |
+            (x @ Value::Str(_), y @ Value::Str(_)) => Value::Bool(x.total_cmp(&y) == Ordering::Less),
             _ => Value::Null,
-        },
-        Expr::LessEq(x, y) => match (eval(&x, funcs, args), eval(&y, funcs, args)) {
-            (Value::Num(x), Value::Num(y)) => Value::Bool(x <= y),
-            (Value::Str(x), Value::Str(y)) => Value::Bool(x <= y),
+        }
+    }
+
+    pub fn lesseq(x: Value, y: Value) -> Value {
+        match (x, y) {
+            (x @ Value::Num(_), y @ Value::Num(_)) |
+            (x @ Value::Str(_), y @ Value::Str(_)) => Value::Bool(x.total_cmp(&y) != Ordering::Greater),
             _ => Value::Null,
-        },
-        Expr::Head(list) => match eval(&list, funcs, args) {
+        }
+    }
+
+    pub fn head(list: Value) -> Value {
+        match list {
             Value::List(items) => items.first().cloned().unwrap_or(Value::Null),
-            Value::Str(s) => s.get(0..1).map(|s| Value::Str(s.to_string())).unwrap_or(Value::Null),
+            Value::Str(s) => s.chars().next().map(|c| Value::Str(c.to_string())).unwrap_or(Value::Null),
+            // Scalars are their own head, and have no tail
             val => val,
-        },
-        Expr::Tail(list) => match eval(&list, funcs, args) {
-            Value::List(items) => items.get(1..).map(|items| Value::List(items.iter().cloned().collect())).unwrap_or(Value::Null),
-            Value::Str(s) => s.get(1..).map(|s| if s.len() == 0 { Value::Null } else { Value::Str(s.to_string()) }).unwrap_or(Value::Null),
+        }
+    }
+
+    pub fn tail(list: Value) -> Value {
+        match list {
+            // The tail of an empty list is still an empty list
+            Value::List(items) => Value::List(items.into_iter().skip(1).collect()),
+            Value::Str(s) => {
+                let mut chars = s.chars();
+                match chars.next() {
+                    Some(_) if !chars.as_str().is_empty() => Value::Str(chars.as_str().to_string()),
+                    _ => Value::Null,
+                }
+            },
             _ => Value::Null,
-        },
-        Expr::Fuse(x, y) => match (eval(&x, funcs, args), eval(&y, funcs, args)) {
+        }
+    }
+
+    pub fn fuse(x: Value, y: Value) -> Value {
+        match (x, y) {
             (Value::List(mut x), Value::List(mut y)) => Value::List({ x.append(&mut y); x }),
             (Value::List(mut x), y) => Value::List({ x.push(y); x }),
             (x, Value::List(mut y)) => Value::List({ let mut v = vec![x]; v.append(&mut y); v }),
             (x, y) => Value::List(vec![x, y]),
-        },
-        Expr::Pair(x, y) => Value::List(vec![eval(&x, funcs, args), eval(&y, funcs, args)]),
-        Expr::Call(f, params) => if let Some(f) = funcs.get(f) {
-            eval(&f.expr, funcs, &params.iter().map(|p| eval(&p, funcs, args)).collect())
-        } else {
-            Value::Null
-        },
-        Expr::Words(x) => if let Value::Str(s) = eval(&x, funcs, args) {
-            Value::List(words(&s).into_iter().map(|s| Value::Str(s)).collect())
+        }
+    }
+
+    pub fn pair(x: Value, y: Value) -> Value {
+        Value::List(vec![x, y])
+    }
+
+    pub fn words(x: Value) -> Value {
+        if let Value::Str(s) = x {
+            Value::List(super::words(&s).into_iter().map(Value::Str).collect())
         } else {
             Value::Null
-        },
-        Expr::Litr(x) => if let Value::Str(s) = eval(&x, funcs, args) {
+        }
+    }
+
+    pub fn litr(x: Value) -> Value {
+        if let Value::Str(s) = x {
             Value::from_str(&s).unwrap_or(Value::Null)
         } else {
             Value::Null
-        },
-        Expr::Input(x) => input(eval(&x, funcs, args).into_string()),
-        Expr::Print(x) => {
-            let val = eval(&x, funcs, args);
-            print(val.clone().into_string());
-            val
-        },
-        Expr::Str(x) => Value::Str(eval(&x, funcs, args).into_string()),
-        Expr::Value(val) => val.clone(),
-        Expr::Local(idx) => args.get(*idx).cloned().unwrap_or(Value::Null),
+        }
     }
-}
 
-fn parse_expr(tokens: &mut slice::Iter<Token>, args: &Vec<String>, func_defs: &HashMap<String, usize>) -> Result<Expr, Error> {
-    Ok(match tokens.next().ok_or(Error::ExpectedToken)? {
-        Token::If => Expr::If(
-            Box::new(parse_expr(tokens, args, func_defs)?),
-            Box::new(parse_expr(tokens, args, func_defs)?),
-            Box::new(parse_expr(tokens, args, func_defs)?),
-        ),
-        Token::Head => Expr::Head(Box::new(parse_expr(tokens, args, func_defs)?)),
-        Token::Tail => Expr::Tail(Box::new(parse_expr(tokens, args, func_defs)?)),
-        Token::Fuse => Expr::Fuse(
-            Box::new(parse_expr(tokens, args, func_defs)?),
-            Box::new(parse_expr(tokens, args, func_defs)?),
-        ),
-        Token::Pair => Expr::Pair(
-            Box::new(parse_expr(tokens, args, func_defs)?),
-            Box::new(parse_expr(tokens, args, func_defs)?),
-        ),
-        Token::Litr => Expr::Litr(Box::new(parse_expr(tokens, args, func_defs)?)),
-        Token::Str => Expr::Str(Box::new(parse_expr(tokens, args, func_defs)?)),
-        Token::Words => Expr::Words(Box::new(parse_expr(tokens, args, func_defs)?)),
-        Token::Input => Expr::Input(Box::new(parse_expr(tokens, args, func_defs)?)),
-        Token::Print => Expr::Print(Box::new(parse_expr(tokens, args, func_defs)?)),
-        Token::Value(v) => Expr::Value(v.clone()),
+    pub fn to_str(x: Value) -> Value {
+        Value::Str(x.to_string())
+    }
 
-        Token::Eq => Expr::Eq(
-            Box::new(parse_expr(tokens, args, func_defs)?),
-            Box::new(parse_expr(tokens, args, func_defs)?),
-        ),
-        Token::Add => Expr::Add(
-            Box::new(parse_expr(tokens, args, func_defs)?),
-            Box::new(parse_expr(tokens, args, func_defs)?),
-        ),
-        Token::Neg => Expr::Neg(Box::new(parse_expr(tokens, args, func_defs)?)),
-        Token::Mul => Expr::Mul(
-            Box::new(parse_expr(tokens, args, func_defs)?),
-            Box::new(parse_expr(tokens, args, func_defs)?),
-        ),
-        Token::Div => Expr::Div(
-            Box::new(parse_expr(tokens, args, func_defs)?),
-            Box::new(parse_expr(tokens, args, func_defs)?),
-        ),
-        Token::Rem => Expr::Rem(
-            Box::new(parse_expr(tokens, args, func_defs)?),
-            Box::new(parse_expr(tokens, args, func_defs)?),
-        ),
-        Token::Less => Expr::Less(
-            Box::new(parse_expr(tokens, args, func_defs)?),
-            Box::new(parse_expr(tokens, args, func_defs)?),
-        ),
-        Token::LessEq => Expr::LessEq(
-            Box::new(parse_expr(tokens, args, func_defs)?),
-            Box::new(parse_expr(tokens, args, func_defs)?),
-        ),
+    pub fn nth(n: Value, list: Value) -> Value {
+        match (n, list) {
+            (Value::Num(n), Value::List(items)) if n >= 0.0 => items.get(n as usize).cloned().unwrap_or(Value::Null),
+            (Value::Num(n), Value::Str(s)) if n >= 0.0 => s.chars().nth(n as usize).map(|c| Value::Str(c.to_string())).unwrap_or(Value::Null),
+            _ => Value::Null,
+        }
+    }
 
-        Token::Ident(i) => {
-            if let Some((idx, _)) = args
-                .iter()
-                .enumerate()
-                .find(|(_, arg)| &i == arg)
-            {
-                Expr::Local(idx)
-            } else if let Some(f_args) = func_defs.get(i.as_str()) {
-                let mut params = vec![];
+    pub fn split(sep: Value, x: Value) -> Value {
+        match (sep, x) {
+            (Value::Str(sep), Value::Str(s)) if !sep.is_empty() => Value::List(s
+                .split(sep.as_str())
+                .map(|s| Value::Str(s.to_string()))
+                .collect()),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn join(sep: Value, x: Value) -> Value {
+        match (sep, x) {
+            (Value::Str(sep), Value::List(items)) => items
+                .into_iter()
+                .map(|item| match item {
+                    Value::Str(s) => Some(s),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|strs| Value::Str(strs.join(&sep)))
+                .unwrap_or(Value::Null),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn idiv(x: Value, y: Value) -> Value {
+        match (x, y) {
+            (Value::Num(_), Value::Num(0.0)) => Value::Null,
+            (Value::Num(x), Value::Num(y)) => Value::Num((x / y).floor()),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn modulo(x: Value, y: Value) -> Value {
+        match (x, y) {
+            (Value::Num(_), Value::Num(0.0)) => Value::Null,
+            (Value::Num(x), Value::Num(y)) => Value::Num(x.rem_euclid(y)),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn take(n: Value, list: Value) -> Value {
+        match (n, list) {
+            (Value::Num(n), Value::List(items)) => Value::List(items.into_iter().take(n.max(0.0) as usize).collect()),
+            (Value::Num(n), Value::Str(s)) => Value::Str(s.chars().take(n.max(0.0) as usize).collect()),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn drop(n: Value, list: Value) -> Value {
+        match (n, list) {
+            (Value::Num(n), Value::List(items)) => Value::List(items.into_iter().skip(n.max(0.0) as usize).collect()),
+            (Value::Num(n), Value::Str(s)) => Value::Str(s.chars().skip(n.max(0.0) as usize).collect()),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn repr(x: Value) -> Value {
+        Value::Str(format!("{:?}", x))
+    }
+
+    pub fn abs(x: Value) -> Value {
+        match x {
+            Value::Num(x) => Value::Num(x.abs()),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn sign(x: Value) -> Value {
+        match x {
+            Value::Num(0.0) => Value::Num(0.0),
+            Value::Num(x) => Value::Num(x.signum()),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn chars(x: Value) -> Value {
+        match x {
+            Value::Str(s) => Value::List(s.chars().map(|c| Value::Str(c.to_string())).collect()),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn from_chars(x: Value) -> Value {
+        match x {
+            Value::List(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    Value::Str(s) => Some(s),
+                    _ => None,
+                })
+                .collect::<Option<String>>()
+                .map(Value::Str)
+                .unwrap_or(Value::Null),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn to_num(x: Value) -> Value {
+        match x {
+            Value::Num(x) => Value::Num(x),
+            // Only finite numbers count, so words like "inf" and "NaN" aren't mistaken for numbers
+            Value::Str(s) => s.trim().parse().ok().filter(|x: &f64| x.is_finite()).map(Value::Num).unwrap_or(Value::Null),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn upper(x: Value) -> Value {
+        match x {
+            Value::Str(s) => Value::Str(s.to_uppercase()),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn lower(x: Value) -> Value {
+        match x {
+            Value::Str(s) => Value::Str(s.to_lowercase()),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn starts_with(x: Value, prefix: Value) -> Value {
+        match (x, prefix) {
+            (Value::Str(s), Value::Str(prefix)) => Value::Bool(s.starts_with(prefix.as_str())),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn contains(x: Value, needle: Value) -> Value {
+        match (x, needle) {
+            (Value::Str(s), Value::Str(needle)) => Value::Bool(s.contains(needle.as_str())),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn trim(x: Value) -> Value {
+        match x {
+            Value::Str(s) => Value::Str(s.trim().to_string()),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn replace(x: Value, from: Value, to: Value) -> Value {
+        match (x, from, to) {
+            (Value::Str(s), Value::Str(from), Value::Str(to)) if !from.is_empty() => Value::Str(s.replace(from.as_str(), &to)),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn band(x: Value, y: Value) -> Value {
+        match (x, y) {
+            (Value::Num(x), Value::Num(y)) => Value::Num(((x as i64) & (y as i64)) as f64),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn bor(x: Value, y: Value) -> Value {
+        match (x, y) {
+            (Value::Num(x), Value::Num(y)) => Value::Num(((x as i64) | (y as i64)) as f64),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn bxor(x: Value, y: Value) -> Value {
+        match (x, y) {
+            (Value::Num(x), Value::Num(y)) => Value::Num(((x as i64) ^ (y as i64)) as f64),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn shl(x: Value, y: Value) -> Value {
+        match (x, y) {
+            (Value::Num(x), Value::Num(y)) if (0.0..64.0).contains(&y) => Value::Num(((x as i64) << (y as u32)) as f64),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn shr(x: Value, y: Value) -> Value {
+        match (x, y) {
+            (Value::Num(x), Value::Num(y)) if (0.0..64.0).contains(&y) => Value::Num(((x as i64) >> (y as u32)) as f64),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn bnot(x: Value) -> Value {
+        match x {
+            Value::Num(x) => Value::Num(!(x as i64) as f64),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn time() -> Value {
+        time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| Value::Num(d.as_secs_f64()))
+            .unwrap_or(Value::Null)
+    }
+
+    pub fn env(x: Value) -> Value {
+        match x {
+            Value::Str(name) => env::var(name).map(Value::Str).unwrap_or(Value::Null),
+            _ => Value::Null,
+        }
+    }
+
+}
+
+fn parse_expr(tokens: &mut slice::Iter<Token>, args: &Vec<String>, func_defs: &HashMap<String, usize>) -> Result<Expr, Error> {
+    Ok(match tokens.next().ok_or(Error::ExpectedToken)? {
+        Token::If => Expr::If(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Head => Expr::Head(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Tail => Expr::Tail(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Fuse => Expr::Fuse(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Pair => Expr::Pair(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Litr => Expr::Litr(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::ToNum => Expr::ToNum(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Str => Expr::Str(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Repr => Expr::Repr(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Assert => Expr::Assert(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Words => Expr::Words(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Chars => Expr::Chars(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::FromChars => Expr::FromChars(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Upper => Expr::Upper(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Lower => Expr::Lower(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::StartsWith => Expr::StartsWith(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Contains => Expr::Contains(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Trim => Expr::Trim(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Replace => Expr::Replace(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Input => Expr::Input(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Env => Expr::Env(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::WriteFile => Expr::WriteFile(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Print => Expr::Print(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Nth => Expr::Nth(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Take => Expr::Take(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Drop => Expr::Drop(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Split => Expr::Split(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Join => Expr::Join(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Value(v) => Expr::Value(v.clone()),
+        Token::Do => {
+            let mut exprs = vec![parse_expr(tokens, args, func_defs)?];
+            while let Some(Token::Semi) = tokens.clone().next() {
+                tokens.next();
+                exprs.push(parse_expr(tokens, args, func_defs)?);
+            }
+            Expr::Seq(exprs)
+        },
+        Token::LParen => {
+            let expr = parse_expr(tokens, args, func_defs)?;
+            match tokens.next() {
+                Some(Token::RParen) => expr,
+                _ => return Err(Error::Expected(Token::RParen)),
+            }
+        },
+
+        Token::Eq => Expr::Eq(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Add => Expr::Add(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Neg => Expr::Neg(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Abs => Expr::Abs(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Sign => Expr::Sign(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Pi => Expr::Pi,
+        Token::E => Expr::E,
+        Token::Random => Expr::Random,
+        Token::Time => Expr::Time,
+        Token::Mul => Expr::Mul(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Div => Expr::Div(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Rem => Expr::Rem(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::IDiv => Expr::IDiv(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Mod => Expr::Mod(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::BAnd => Expr::BAnd(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::BOr => Expr::BOr(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::BXor => Expr::BXor(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Shl => Expr::Shl(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::Shr => Expr::Shr(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::BNot => Expr::BNot(Box::new(parse_expr(tokens, args, func_defs)?)),
+        Token::Less => Expr::Less(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+        Token::LessEq => Expr::LessEq(
+            Box::new(parse_expr(tokens, args, func_defs)?),
+            Box::new(parse_expr(tokens, args, func_defs)?),
+        ),
+
+        Token::Ident(i) => {
+            if let Some((idx, _)) = args
+                .iter()
+                .enumerate()
+                .find(|(_, arg)| arg.as_str() != "_" && &i == arg)
+            {
+                Expr::Local(idx)
+            } else if let Some(f_args) = func_defs.get(i.as_str()) {
+                let mut params = vec![];
                 for _ in 0..*f_args {
-                    params.push(parse_expr(tokens, args, func_defs)?);
+                    match tokens.clone().next() {
+                        None | Some(Token::Fn) => return Err(Error::IncorrectArity(i.clone(), *f_args, params.len())),
+                        _ => params.push(parse_expr(tokens, args, func_defs)?),
+                    }
                 }
                 Expr::Call(i.clone(), params)
             } else {
@@ -288,10 +1335,9 @@ fn parse_expr(tokens: &mut slice::Iter<Token>, args: &Vec<String>, func_defs: &H
     })
 }
 
-fn parse_funcs(mut tokens: slice::Iter<Token>) -> Result<HashMap<String, Func>, Error> {
-    let mut funcs = HashMap::new();
+fn parse_funcs(mut tokens: slice::Iter<Token>, mut func_defs: HashMap<String, usize>) -> Result<BTreeMap<String, Func>, Error> {
+    let mut funcs = BTreeMap::new();
 
-    let mut func_defs = HashMap::new();
     tokens
         .clone()
         .scan((None, &mut func_defs), |(state, funcs), tok| {
@@ -321,7 +1367,8 @@ fn parse_funcs(mut tokens: slice::Iter<Token>) -> Result<HashMap<String, Func>,
     loop {
         match tokens.next() {
             Some(Token::Fn) => {},
-            _ => return Ok(funcs),
+            Some(tok) => return Err(Error::Unexpected(tok.clone())),
+            None => return Ok(funcs),
         }
 
         let name = match tokens.next() {
@@ -329,6 +1376,10 @@ fn parse_funcs(mut tokens: slice::Iter<Token>) -> Result<HashMap<String, Func>,
             _ => return Err(Error::Expected(Token::Fn)),
         };
 
+        if funcs.contains_key(&name) {
+            return Err(Error::Redefined(name));
+        }
+
         let mut args = vec![];
         loop {
             match tokens.next() {
@@ -355,11 +1406,18 @@ fn words(s: &str) -> Vec<String> {
         .chain(Some(' '))
         .scan((false, String::new()), |(in_str, buf), c| {
             match c {
+                // A string literal always forms a word of its own, so that any parens around it are split off
+                // rather than read as part of the string
                 '"' /*"*/ => if *in_str {
                     *in_str = false;
+                    let s = buf.clone();
+                    buf.clear();
+                    return Some(s);
                 } else {
-                    buf.push('"' /*"*/);
+                    let s = buf.clone();
+                    *buf = '"' /*"*/.to_string();
                     *in_str = true;
+                    return Some(s);
                 },
                 c if c.is_whitespace() && !*in_str => {
                     let s = buf.clone();
@@ -374,44 +1432,276 @@ fn words(s: &str) -> Vec<String> {
         .collect()
 }
 
+fn split_parens(s: String) -> Vec<String> {
+    // Strings are left untouched, everything else has its leading '(' and trailing ')' split off
+    if s.starts_with('"' /*"*/) || s.len() <= 1 {
+        return vec![s];
+    }
+
+    let inner = s.trim_start_matches('(');
+    let opens = s.len() - inner.len();
+    let ident = inner.trim_end_matches(')');
+    let closes = inner.len() - ident.len();
+
+    let mut words = vec!["(".to_string(); opens];
+    if !ident.is_empty() {
+        words.push(ident.to_string());
+    }
+    words.extend(vec![")".to_string(); closes]);
+    words
+}
+
 fn lex(code: &str) -> Vec<Token> {
     words(code)
         .into_iter()
-        .map(|s| match s.as_str() {
-            "fn" => Token::Fn,
-            "is" => Token::Is,
-            "if" => Token::If,
-            "__head" => Token::Head,
-            "__tail" => Token::Tail,
-            "__fuse" => Token::Fuse,
-            "__pair" => Token::Pair,
-            "__litr" => Token::Litr,
-            "__str" => Token::Str,
-            "__words" => Token::Words,
-            "__input" => Token::Input,
-            "__print" => Token::Print,
-            "__eq" => Token::Eq,
-            "__add" => Token::Add,
-            "__neg" => Token::Neg,
-            "__mul" => Token::Mul,
-            "__div" => Token::Div,
-            "__rem" => Token::Rem,
-            "__less" => Token::Less,
-            "__lesseq" => Token::LessEq,
-            s => if let Some(v) = Value::from_str(s) {
+        .flat_map(split_parens)
+        .map(|s| match KEYWORDS.iter().find(|(name, _)| *name == s) {
+            Some((_, token)) => token.clone(),
+            None => if let Some(v) = Value::from_str(&s) {
                 Token::Value(v)
             } else {
-                Token::Ident(s.to_string())
-            }
+                Token::Ident(s)
+            },
         })
         .collect::<Vec<_>>()
 }
 
-fn with_core(code: &str) -> String {
-    String::from(include_str!("atto/core.at")) + code
+fn format_expr(expr: &Expr, args: &[String], depth: usize) -> String {
+    let indent = "\t".repeat(depth);
+
+    match expr {
+        Expr::If(pred, good, bad) => format!(
+            "if {}\n{}\t{}\n{}{}",
+            format_expr(pred, args, depth),
+            indent, format_expr(good, args, depth + 1),
+            indent, format_expr(bad, args, depth),
+        ),
+        // Comments go on their own line, above the expression they describe
+        Expr::Call(name, params) if name == "#" && params.len() == 2 => format!(
+            "# {}\n{}{}",
+            format_expr(&params[0], args, depth),
+            indent, format_expr(&params[1], args, depth),
+        ),
+        // Each expression in a sequence goes on its own line. Earlier ones are parenthesised if they contain a
+        // sequence of their own, since it would otherwise swallow the expressions that follow it
+        Expr::Seq(exprs) => format!("do {}", exprs
+            .iter()
+            .enumerate()
+            .map(|(i, expr)| if i + 1 < exprs.len() && contains_seq(expr) {
+                // A closing paren straight after a string literal would be read as part of the string
+                let inner = format_expr(expr, args, depth);
+                if inner.ends_with('"' /*"*/) {
+                    format!("({} )", inner)
+                } else {
+                    format!("({})", inner)
+                }
+            } else {
+                format_expr(expr, args, depth)
+            })
+            .collect::<Vec<_>>()
+            .join(&format!("\n{}; ", indent))),
+        Expr::Value(Value::Str(s)) => format!("\"{}\"", s),
+        Expr::Value(val) => val.to_string(),
+        Expr::Local(idx) => args[*idx].clone(),
+        Expr::Call(name, _) => format_call(name, expr, args, depth),
+        expr => format_call(&expr.token().unwrap().to_string(), expr, args, depth),
+    }
+}
+
+fn contains_seq(expr: &Expr) -> bool {
+    let mut found = matches!(expr, Expr::Seq(_));
+    expr.visit_children(|child| found |= contains_seq(child));
+    found
+}
+
+fn format_call(name: &str, expr: &Expr, args: &[String], depth: usize) -> String {
+    let mut s = name.to_string();
+    expr.visit_children(|param| s += &format!(" {}", format_expr(param, args, depth)));
+    s
+}
+
+fn format_func(name: &str, func: &Func) -> String {
+    format!(
+        "fn {}{} is\n\t{}\n",
+        name,
+        func.args.iter().map(|arg| format!(" {}", arg)).collect::<String>(),
+        format_expr(&func.expr, &func.args, 1),
+    )
+}
+
+struct Interpreter {
+    funcs: BTreeMap<String, Func>,
+    prelude: Option<String>,
+    memoise: bool,
+    lazy: bool,
+    // The state of the random number generator, carried from one evaluation to the next
+    rng: Cell<u64>,
+    fuel: Option<u64>,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        let mut interp = Self {
+            funcs: BTreeMap::new(),
+            prelude: Some(include_str!("atto/core.at").to_string()),
+            memoise: false,
+            lazy: false,
+            rng: Cell::new(time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0)),
+            fuel: None,
+        };
+        interp.reset().expect("Failed to parse the core library");
+        interp
+    }
+
+    /// Forget every function apart from those in the prelude
+    ///
+    /// If the prelude fails to parse, no functions are left defined.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.funcs.clear();
+        match self.prelude.clone() {
+            Some(prelude) => self.load(&prelude),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace the prelude (the core library by default) with other code, or with nothing at all
+    ///
+    /// This forgets every function that has been loaded so far.
+    pub fn set_prelude(&mut self, prelude: Option<&str>) -> Result<(), Error> {
+        self.prelude = prelude.map(|p| p.to_string());
+        self.reset()
+    }
+
+    /// Cache the results of calls to functions that perform no I/O. This has no effect in lazy mode,
+    /// since building a cache key would force arguments that might never be used.
+    pub fn set_memoise(&mut self, memoise: bool) {
+        self.memoise = memoise;
+    }
+
+    /// Only evaluate function arguments when they are first used
+    pub fn set_lazy(&mut self, lazy: bool) {
+        self.lazy = lazy;
+    }
+
+    /// Limit each evaluation to `fuel` expressions, after which it fails with `Error::OutOfFuel`
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Seed the random number generator so that `__random` produces a reproducible sequence
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng.set(seed);
+    }
+
+    fn pure_funcs(&self) -> HashSet<String> {
+        let mut impure = HashSet::new();
+        loop {
+            let count = impure.len();
+            for (name, f) in &self.funcs {
+                if f.expr.is_impure(&impure) {
+                    impure.insert(name.clone());
+                }
+            }
+            if impure.len() == count {
+                break;
+            }
+        }
+
+        self.funcs
+            .keys()
+            .filter(|name| !impure.contains(*name))
+            .cloned()
+            .collect()
+    }
+
+    fn func_defs(&self) -> HashMap<String, usize> {
+        self.funcs
+            .iter()
+            .map(|(name, f)| (name.clone(), f.args.len()))
+            .collect()
+    }
+
+    /// Parse the functions in `code`, replacing any existing functions of the same name
+    pub fn load(&mut self, code: &str) -> Result<(), Error> {
+        let funcs = parse_funcs(lex(code).iter(), self.func_defs())?;
+        if let Some(main) = funcs.get("main") {
+            if !main.args.is_empty() {
+                return Err(Error::MainArity(main.args.len()));
+            }
+        }
+        self.funcs.extend(funcs);
+        Ok(())
+    }
+
+    /// Parse a single expression against the loaded functions, without requiring a `main`
+    pub fn parse(&self, code: &str) -> Result<Expr, Error> {
+        let tokens = lex(code);
+        let mut tokens = tokens.iter();
+        let expr = parse_expr(&mut tokens, &vec![], &self.func_defs())?;
+        match tokens.next() {
+            Some(t) => Err(Error::Unexpected(t.clone())),
+            None => Ok(expr),
+        }
+    }
+
+    /// Evaluate a single expression against the loaded functions
+    pub fn eval(&self, code: &str) -> Result<Value, Error> {
+        self.eval_expr(&self.parse(code)?)
+    }
+
+    /// Output is buffered, and flushed when evaluation finishes (successfully or not) or stops to read input
+    fn eval_expr(&self, expr: &Expr) -> Result<Value, Error> {
+        self.eval_expr_to(expr, Box::new(io::BufWriter::new(io::stdout())))
+    }
+
+    fn eval_expr_to(&self, expr: &Expr, out: Box<dyn Write + '_>) -> Result<Value, Error> {
+        let mut ctx = Ctx::new(out, self.lazy, if self.memoise && !self.lazy { self.pure_funcs() } else { HashSet::new() }, self.rng.get(), self.fuel);
+        let val = eval(expr, &self.funcs, &[], &mut ctx);
+        // Flushed on errors too, so that output printed before an error isn't lost
+        let _ = ctx.out.flush();
+        self.rng.set(ctx.rng);
+        val
+    }
+
+    /// Parse `code` and render its functions back into canonically formatted source, in their original order
+    pub fn format(&self, code: &str) -> Result<String, Error> {
+        let tokens = lex(code);
+        let funcs = parse_funcs(tokens.iter(), self.func_defs())?;
+        Ok(tokens
+            .windows(2)
+            .filter_map(|w| match w {
+                [Token::Fn, Token::Ident(name)] => Some(funcs
+                    .get(name)
+                    .map(|f| format_func(name, f))
+                    .ok_or_else(|| Error::CannotFind(name.clone()))),
+                _ => None,
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .join("\n"))
+    }
+
+    /// Evaluate `main`, if it exists
+    pub fn run(&self) -> Result<Value, Error> {
+        match self.funcs.get("main") {
+            Some(main) => self.eval_expr(&main.expr),
+            None => Ok(Value::Null),
+        }
+    }
+
+    /// Evaluate the function `name` in place of `main`. It must exist and take no arguments
+    pub fn run_entry(&self, name: &str) -> Result<Value, Error> {
+        match self.funcs.get(name) {
+            Some(f) if !f.args.is_empty() => Err(Error::IncorrectArity(name.to_string(), f.args.len(), 0)),
+            Some(f) => self.eval_expr(&f.expr),
+            None => Err(Error::CannotFind(name.to_string())),
+        }
+    }
 }
 
-fn prompt() {
+fn prompt(mut interp: Interpreter) {
     /*
     let code = include_str!("eval.at");
 
@@ -436,55 +1726,785 @@ fn prompt() {
 
     println!("Welcome to the Atto prompt.");
     println!("The core library is included by default.");
+    println!("Functions remain defined until you enter ':reset'.");
+    println!("Enter ':load <file>' to load the functions in a file.");
 
     let mut rl = Editor::<()>::new();
     while let Ok(line) = rl.readline(">> ") {
         rl.add_history_entry(line.as_ref());
 
-        let _ = {
-            let tokens = lex(&with_core(&line));
-
-            parse_funcs(tokens.iter()).map(|funcs| {
-                if let Some(main) = funcs.get("main") {
-                    eval(&main.expr, &funcs, &mut vec![])
-                } else {
-                    Value::Null
-                }
-            })
-            .and_then(|_| parse_expr(&mut tokens.iter(), &vec![], &HashMap::new()).map(|expr| {
-                eval(&expr, &HashMap::new(), &mut vec![])
-            }))
+        let _ = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => continue,
+            [":reset"] => interp.reset(),
+            [":load", fname] => match read_file(fname) {
+                Some(code) => interp.load(&code),
+                None => continue,
+            },
+            _ => match lex(&line).first() {
+                Some(Token::Fn) => interp.load(&line),
+                _ => interp.eval(&line).map(|val| println!("{}", val)),
+            },
         }
-            .map(|val| println!("{}", val.into_string()))
-            .map_err(|err| print!("{:?}", err));
+            .map_err(|err| eprintln!("Error: {}", err));
     }
 }
 
-fn exec(fname: &str) {
-    let mut code = String::new();
-    match fs::File::open(fname) {
-        Ok(mut file) => { file.read_to_string(&mut code).unwrap(); },
-        Err(_) => println!("Could not open file '{}'", fname),
+fn read_file(fname: &str) -> Option<String> {
+    fs::read_to_string(fname)
+        .map(|code| if code.starts_with("#!") {
+            // Skip the shebang line, keeping the newline so that later lines are unaffected
+            code[code.find('\n').unwrap_or(code.len())..].to_string()
+        } else {
+            code
+        })
+        .map_err(|_| eprintln!("Error: Could not open file '{}'", fname))
+        .ok()
+}
+
+fn exec(mut interp: Interpreter, fname: &str, entry: Option<&str>) {
+    let code = match read_file(fname) {
+        Some(code) => code,
+        None => process::exit(1),
+    };
+
+    let result = interp.load(&code).and_then(|_| match entry {
+        Some(name) => interp.run_entry(name),
+        None => interp.run(),
+    });
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        process::exit(1);
     }
+}
 
-    let _ = parse_funcs(lex(&with_core(&code)).iter()).map(|funcs| {
-        if let Some(main) = funcs.get("main") {
-            eval(&main.expr, &funcs, &mut vec![])
-        } else {
-            Value::Null
-        }
-    })
-        .map_err(|err| print!("{:?}", err));
+fn exec_expr(interp: Interpreter, code: &str) {
+    match interp.eval(code) {
+        Ok(val) => println!("{}", val),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        },
+    }
+}
+
+fn format(interp: Interpreter, fname: &str) {
+    let code = match read_file(fname) {
+        Some(code) => code,
+        None => process::exit(1),
+    };
+
+    match interp.format(&code) {
+        Ok(code) => print!("{}", code),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        },
+    }
 }
 
 fn usage() {
-    println!("Usage: atto [file]");
+    println!("Usage: atto [--memoise] [--lazy] [--no-core] [--seed <n>] [--fuel <n>] [--entry <name>] [file | -e <expr> | fmt <file>]");
 }
 
 fn main() {
-    match &env::args().nth(1) {
-        None => prompt(),
-        Some(arg) if env::args().count() == 2 => exec(arg),
-        Some(_) => usage(),
+    let mut interp = Interpreter::new();
+    let mut args = vec![];
+    let mut entry = None;
+    let mut env_args = env::args().skip(1);
+    while let Some(arg) = env_args.next() {
+        match arg.as_str() {
+            "--memoise" => interp.set_memoise(true),
+            "--lazy" => interp.set_lazy(true),
+            "--no-core" => interp.set_prelude(None).expect("An empty prelude always loads"),
+            "--seed" => match env_args.next().and_then(|seed| seed.parse().ok()) {
+                Some(seed) => interp.set_seed(seed),
+                None => return usage(),
+            },
+            "--fuel" => match env_args.next().and_then(|fuel| fuel.parse().ok()) {
+                Some(fuel) => interp.set_fuel(Some(fuel)),
+                None => return usage(),
+            },
+            "--entry" => match env_args.next() {
+                Some(name) => entry = Some(name),
+                None => return usage(),
+            },
+            _ => args.push(arg),
+        }
+    }
+
+    match args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>().as_slice() {
+        [] => prompt(interp),
+        ["-e", code] => exec_expr(interp, code),
+        ["fmt", fname] => format(interp, fname),
+        [fname] => exec(interp, fname, entry.as_deref()),
+        _ => usage(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Evaluate `code` as an expression, capturing anything it prints
+    fn run(interp: &Interpreter, code: &str) -> (Result<Value, Error>, String) {
+        let mut out = Vec::new();
+        let val = interp.parse(code).and_then(|expr| interp.eval_expr_to(&expr, Box::new(&mut out)));
+        (val, String::from_utf8(out).unwrap())
+    }
+
+    fn eval_str(code: &str) -> Value {
+        run(&Interpreter::new(), code).0.unwrap()
+    }
+
+    fn load(code: &str) -> Interpreter {
+        let mut interp = Interpreter::new();
+        interp.load(code).unwrap();
+        interp
+    }
+
+    // A path in the temporary directory that no other test uses
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("atto-test-{}-{}", process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn num(x: f64) -> Value {
+        Value::Num(x)
+    }
+
+    fn string(s: &str) -> Value {
+        Value::Str(s.to_string())
+    }
+
+    #[test]
+    fn parens_group_sub_expressions() {
+        assert_eq!(eval_str("* (+ 1 2) 4"), num(12.0));
+        assert_eq!(eval_str("((+ 1 2))"), num(3.0));
+        assert_eq!(split_parens("((+".to_string()), vec!["(", "(", "+"]);
+        assert_eq!(split_parens("2))".to_string()), vec!["2", ")", ")"]);
+        assert!(Interpreter::new().parse("(+ 1 2").is_err());
+        // A string literal can end a group, and can contain parens of its own
+        assert_eq!(eval_str("(__str \"hi\")"), string("hi"));
+        assert_eq!(eval_str("pair (\"x)\") (\"(y\")"), Value::List(vec![string("x)"), string("(y")]));
+        assert_eq!(words("(f \"a b)\"))"), vec!["(f", "\"a b)", "))"]);
+    }
+
+    #[test]
+    fn wildcard_params_bind_nothing() {
+        let interp = load("fn second _ y is\n\ty\nfn third _ _ z is\n\tz\n");
+        assert_eq!(run(&interp, "second 5 3").0.unwrap(), num(3.0));
+        assert_eq!(run(&interp, "third 1 2 3").0.unwrap(), num(3.0));
+        assert!(Interpreter::new().load("fn f _ is\n\t_\n").is_err());
+    }
+
+    #[test]
+    fn nth_indexes_lists_and_strings() {
+        assert_eq!(eval_str("__nth 1 pair 4 5"), num(5.0));
+        assert_eq!(eval_str("__nth 2 pair 4 5"), Value::Null);
+        assert_eq!(eval_str("__nth -1 pair 4 5"), Value::Null);
+        assert_eq!(eval_str("__nth 1 \"abc\""), string("b"));
+        assert_eq!(eval_str("nth 0 \"abc\""), string("a"));
+    }
+
+    #[test]
+    fn split_and_join_strings() {
+        let parts = Value::List(vec![string("a"), string(""), string("b")]);
+        assert_eq!(eval_str("__split \",\" \"a,,b\""), parts);
+        assert_eq!(eval_str("__join \"-\" __split \",\" \"a,,b\""), string("a--b"));
+        assert_eq!(eval_str("__split \"\" \"ab\""), Value::Null);
+        assert_eq!(eval_str("__join \",\" pair \"a\" 1"), Value::Null);
+    }
+
+    #[test]
+    fn values_display() {
+        let val = Value::List(vec![num(1.5), string("a b"), Value::Bool(true), Value::Null, Value::List(vec![])]);
+        assert_eq!(val.to_string(), "[1.5, a b, true, null, []]");
+        assert_eq!(num(-3.0).to_string(), "-3");
+    }
+
+    #[test]
+    fn interpreter_loads_and_runs() {
+        let interp = load("fn double x is\n\t* 2 x\nfn main is\n\tdouble 21\n");
+        assert_eq!(interp.run().unwrap(), num(42.0));
+        assert_eq!(interp.eval("double 4").unwrap(), num(8.0));
+        assert_eq!(Interpreter::new().run().unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn value_iter_yields_list_items() {
+        let list = Value::List(vec![num(1.0), num(2.0)]);
+        assert_eq!(list.iter().cloned().collect::<Vec<_>>(), vec![num(1.0), num(2.0)]);
+        assert_eq!(string("ab").iter().count(), 0);
+        assert_eq!(Value::Null.iter().count(), 0);
+    }
+
+    #[test]
+    fn loaded_functions_persist_until_reset() {
+        let mut interp = load("fn one is\n\t1\n");
+        interp.load("fn two is\n\t+ one one\n").unwrap();
+        assert_eq!(interp.eval("two").unwrap(), num(2.0));
+        interp.load("fn one is\n\t5\n").unwrap();
+        assert_eq!(interp.eval("two").unwrap(), num(10.0));
+        interp.reset().unwrap();
+        assert!(interp.eval("two").is_err());
+        assert_eq!(interp.eval("+ 1 2").unwrap(), num(3.0));
+    }
+
+    #[test]
+    fn files_can_be_loaded() {
+        let fname = temp_path("load.at");
+        fs::write(&fname, "fn triple x is\n\t* 3 x\n").unwrap();
+        let mut interp = Interpreter::new();
+        interp.load(&read_file(&fname).unwrap()).unwrap();
+        fs::remove_file(&fname).unwrap();
+        assert_eq!(interp.eval("triple 2").unwrap(), num(6.0));
+        assert_eq!(read_file(&temp_path("missing.at")), None);
+    }
+
+    #[test]
+    fn expressions_evaluate_without_main() {
+        let interp = Interpreter::new();
+        assert_eq!(interp.eval("len pair 1 pair 2 3").unwrap(), num(2.0));
+        assert!(interp.eval("+ 1 2 3").is_err());
+        assert!(interp.eval("").is_err());
+    }
+
+    #[test]
+    fn errors_display_readably() {
+        let interp = Interpreter::new();
+        assert_eq!(interp.eval("nope").unwrap_err().to_string(), "Cannot find function 'nope'");
+        assert_eq!(interp.eval("+ 1").unwrap_err().to_string(), "Function '+' expects 2 arguments, found 1");
+        assert_eq!(interp.eval("1 2").unwrap_err().to_string(), "Unexpected '2'");
+    }
+
+    #[test]
+    fn idiv_floors_and_mod_is_euclidean() {
+        assert_eq!(eval_str("idiv 7 2"), num(3.0));
+        assert_eq!(eval_str("idiv -7 2"), num(-4.0));
+        assert_eq!(eval_str("mod -7 2"), num(1.0));
+        assert_eq!(eval_str("mod 7 -2"), num(1.0));
+        assert_eq!(eval_str("idiv 1 0"), Value::Null);
+        assert_eq!(eval_str("mod 1 0"), Value::Null);
+    }
+
+    #[test]
+    fn division_by_zero_is_null() {
+        assert_eq!(eval_str("/ 1 0"), Value::Null);
+        assert_eq!(eval_str("/ 1 -0"), Value::Null);
+        assert_eq!(eval_str("% 1 0"), Value::Null);
+        assert_eq!(eval_str("/ 0 4"), num(0.0));
+        assert_eq!(eval_str("% 7 4"), num(3.0));
+    }
+
+    #[test]
+    fn nan_equals_itself() {
+        let nan = num(f64::NAN);
+        assert_eq!(nan, nan);
+        assert_eq!(builtins::eq(nan.clone(), nan.clone()), Value::Bool(true));
+        assert_eq!(Value::List(vec![nan.clone()]), Value::List(vec![nan.clone()]));
+        assert_ne!(nan, num(1.0));
+        assert_eq!(builtins::less(nan.clone(), nan.clone()), Value::Bool(false));
+        assert_eq!(builtins::lesseq(nan.clone(), nan.clone()), Value::Bool(true));
+    }
+
+    #[test]
+    fn total_cmp_orders_every_value() {
+        let sorted = vec![
+            Value::Null,
+            Value::Bool(false),
+            Value::Bool(true),
+            num(f64::NEG_INFINITY),
+            num(-1.0),
+            num(2.0),
+            num(f64::NAN),
+            string("a"),
+            string("b"),
+            Value::List(vec![]),
+            Value::List(vec![num(1.0)]),
+            Value::List(vec![num(1.0), num(0.0)]),
+            Value::List(vec![num(2.0)]),
+        ];
+        for (i, x) in sorted.iter().enumerate() {
+            for (j, y) in sorted.iter().enumerate() {
+                assert_eq!(x.total_cmp(y), i.cmp(&j), "{:?} vs {:?}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn take_and_drop_slice_lists_and_strings() {
+        assert_eq!(eval_str("take 1 pair 1 2"), Value::List(vec![num(1.0)]));
+        assert_eq!(eval_str("drop 1 fuse pair 1 2 wrap 3"), Value::List(vec![num(2.0), num(3.0)]));
+        assert_eq!(eval_str("take 10 \"abc\""), string("abc"));
+        assert_eq!(eval_str("drop 2 \"abc\""), string("c"));
+        assert_eq!(eval_str("take -1 \"abc\""), string(""));
+        assert_eq!(eval_str("drop 1 5"), Value::Null);
+    }
+
+    #[test]
+    fn shebang_lines_are_skipped() {
+        let fname = temp_path("shebang.at");
+        fs::write(&fname, "#!/usr/bin/env atto\nfn main is\n\t1\n").unwrap();
+        let code = read_file(&fname).unwrap();
+        fs::remove_file(&fname).unwrap();
+        assert_eq!(code, "\nfn main is\n\t1\n");
+        assert!(load(&code).funcs.contains_key("main"));
+    }
+
+    #[test]
+    fn main_must_take_no_arguments() {
+        let err = Interpreter::new().load("fn main x is\n\tx\n").unwrap_err();
+        assert!(matches!(err, Error::MainArity(1)), "{:?}", err);
+    }
+
+    #[test]
+    fn missing_arguments_are_rejected_when_parsing() {
+        let err = Interpreter::new().load("fn main is\n\tpair 1\n").unwrap_err();
+        assert!(matches!(&err, Error::IncorrectArity(name, 2, 1) if name == "pair"), "{:?}", err);
+        let err = Interpreter::new().load("fn f x y is\n\tx\nfn main is\n\tf 1\n").unwrap_err();
+        assert!(matches!(&err, Error::IncorrectArity(name, 2, 1) if name == "f"), "{:?}", err);
+        // Extra arguments are left over after the body, which must not be mistaken for the end of the file
+        let err = Interpreter::new().load("fn f is\n\t+ 1 2 3\n\nfn main is\n\tprint 5\n").unwrap_err();
+        assert!(matches!(&err, Error::Unexpected(Token::Value(Value::Num(x))) if *x == 3.0), "{:?}", err);
+    }
+
+    #[test]
+    fn functions_cannot_be_defined_twice_in_one_source() {
+        let err = Interpreter::new().load("fn f is\n\t1\nfn f is\n\t2\n").unwrap_err();
+        assert!(matches!(&err, Error::Redefined(name) if name == "f"), "{:?}", err);
+        // Redefining a core function, or one loaded earlier, is still allowed
+        let mut interp = load("fn len l is\n\t0\n");
+        interp.load("fn len l is\n\t1\n").unwrap();
+        assert_eq!(interp.eval("len empty").unwrap(), num(1.0));
+    }
+
+    #[test]
+    fn formatting_round_trips() {
+        let interp = Interpreter::new();
+        let sources = [
+            include_str!("atto/core.at"),
+            include_str!("../examples/maths.at"),
+            include_str!("../examples/lists.at"),
+            include_str!("../examples/adventure.at"),
+            "fn f x _ is\n\tif = x 0 (* 2 (+ x 1)) do print x ; - x 1\n",
+            "fn main is\n\tdo (do 1 ; \"a\" ) ; 2\n",
+        ];
+        for code in sources.iter() {
+            let formatted = interp.format(code).unwrap();
+            assert_eq!(interp.format(&formatted).unwrap(), formatted);
+            let parse = |code: &str| parse_funcs(lex(code).iter(), interp.func_defs()).unwrap();
+            assert!(parse(code) == parse(&formatted), "{}", formatted);
+        }
+
+        // Functions that fail to parse are reported rather than formatted
+        assert!(interp.format("fn f is\n\t+ 1 2 3\n\nfn main is\n\tprint 5\n").is_err());
+    }
+
+    #[test]
+    fn visit_children_visits_operands_in_order() {
+        let interp = Interpreter::new();
+        let children = |code: &str| {
+            let mut children = vec![];
+            interp.parse(code).unwrap().visit_children(|child| children.push(child.clone()));
+            children
+        };
+        let values = |xs: &[f64]| xs.iter().map(|x| Expr::Value(num(*x))).collect::<Vec<_>>();
+        assert_eq!(children("if true 1 2"), vec![Expr::Value(Value::Bool(true)), Expr::Value(num(1.0)), Expr::Value(num(2.0))]);
+        assert_eq!(children("__pair 1 2"), values(&[1.0, 2.0]));
+        assert_eq!(children("__neg 3"), values(&[3.0]));
+        assert_eq!(children("pair 4 5"), values(&[4.0, 5.0]));
+        assert_eq!(children("do 1 ; 2 ; 3"), values(&[1.0, 2.0, 3.0]));
+        assert_eq!(children("__pi"), vec![]);
+    }
+
+    #[test]
+    fn input_strips_line_endings_and_signals_end_of_input() {
+        let mut reader = io::Cursor::new("one\ntwo\r\n\nthree");
+        assert_eq!(read_line(&mut reader), string("one"));
+        assert_eq!(read_line(&mut reader), string("two"));
+        assert_eq!(read_line(&mut reader), string(""));
+        assert_eq!(read_line(&mut reader), string("three"));
+        assert_eq!(read_line(&mut reader), Value::Null);
+    }
+
+    #[test]
+    fn prints_go_to_the_evaluation_writer() {
+        let code = format!("do {}", (1..=500).map(|n| format!("print {}", n)).collect::<Vec<_>>().join(" ; "));
+        let (val, out) = run(&Interpreter::new(), &code);
+        assert_eq!(val.unwrap(), num(500.0));
+        assert_eq!(out, (1..=500).map(|n| format!("{}\n", n)).collect::<String>());
+    }
+
+    #[test]
+    fn deeply_nested_lists_display_and_compare() {
+        const DEPTH: usize = 100_000;
+        let nested = || (0..DEPTH).fold(Value::Null, |val, _| Value::List(vec![val]));
+        let (x, y) = (nested(), nested());
+        assert_eq!(x.to_string(), format!("{}null{}", "[".repeat(DEPTH), "]".repeat(DEPTH)));
+        assert_eq!(x, y);
+        // Dropping is still recursive, so take the lists apart one level at a time
+        for mut val in [x, y] {
+            while let Value::List(mut items) = val {
+                val = items.pop().unwrap_or(Value::Null);
+            }
+        }
+    }
+
+    #[test]
+    fn equal_values_hash_equally() {
+        use std::collections::hash_map::DefaultHasher;
+
+        let hash = |val: &Value| {
+            let mut hasher = DefaultHasher::new();
+            val.hash(&mut hasher);
+            hasher.finish()
+        };
+        let pairs = [
+            (num(f64::NAN), num(-f64::NAN)),
+            (num(0.0), num(-0.0)),
+            (Value::List(vec![num(1.0), string("a")]), eval_str("pair 1 \"a\"")),
+        ];
+        for (x, y) in pairs.iter() {
+            assert_eq!(x, y);
+            assert_eq!(hash(x), hash(y), "{:?} and {:?}", x, y);
+        }
+
+        let mut map = HashMap::new();
+        map.insert(eval_str("pair 1 pair 2 3"), "first");
+        map.insert(Value::List(vec![num(1.0), Value::List(vec![num(2.0), num(3.0)])]), "second");
+        assert_eq!(map.len(), 1);
+        assert_eq!(map[&eval_str("pair 1 pair 2 3")], "second");
+    }
+
+    #[test]
+    fn memoisation_avoids_repeated_calls() {
+        let mut interp = load("fn fib n is\n\tif __less n 2\n\t\tn\n\t+ fib - n 1 fib - n 2\nfn noisy n is\n\tprint fib n\n");
+        // Count the expressions evaluated, using fuel as the counter
+        let evaluations = |interp: &Interpreter, code: &str| {
+            let pure = if interp.memoise { interp.pure_funcs() } else { HashSet::new() };
+            let mut ctx = Ctx::new(Box::new(io::sink()), false, pure, 0, Some(u64::MAX));
+            assert_eq!(eval(&interp.parse(code).unwrap(), &interp.funcs, &[], &mut ctx).unwrap(), num(6765.0));
+            u64::MAX - ctx.fuel.unwrap()
+        };
+        let plain = evaluations(&interp, "fib 20");
+        interp.set_memoise(true);
+        let memoised = evaluations(&interp, "fib 20");
+        assert!(memoised * 100 < plain, "{} vs {}", memoised, plain);
+
+        let pure = interp.pure_funcs();
+        assert!(pure.contains("fib"));
+        assert!(!pure.contains("noisy"));
+
+        // Arguments that are equal but distinguishable, like 0 and -0, are cached separately
+        interp.load("fn f x is\n\tstr x\n").unwrap();
+        assert_eq!(interp.eval("pair f 0 f -0").unwrap(), Value::List(vec![string("0"), string("-0")]));
+    }
+
+    #[test]
+    fn lazy_arguments_are_only_evaluated_when_used() {
+        let mut interp = load("fn first x y is\n\tx\nfn loop x is\n\tloop x\nfn twice x is\n\t+ x x\n");
+        let (val, out) = run(&interp, "first 1 print \"unused\"");
+        assert_eq!((val.unwrap(), out.as_str()), (num(1.0), "unused\n"));
+
+        interp.set_lazy(true);
+        let (val, out) = run(&interp, "first 1 print \"unused\"");
+        assert_eq!((val.unwrap(), out.as_str()), (num(1.0), ""));
+        // Arguments are evaluated at most once
+        let (val, out) = run(&interp, "twice print 2");
+        assert_eq!((val.unwrap(), out.as_str()), (num(4.0), "2\n"));
+
+        // Memoisation would force the unused argument, so it's ignored in lazy mode
+        interp.set_memoise(true);
+        interp.set_fuel(Some(1000));
+        assert_eq!(run(&interp, "first 1 loop 0").0.unwrap(), num(1.0));
+    }
+
+    #[test]
+    fn repr_shows_the_debug_representation() {
+        assert_eq!(eval_str("repr \"a\""), string("Str(\"a\")"));
+        assert_eq!(eval_str("repr pair 1 null"), string("List([Num(1.0), Null])"));
+    }
+
+    #[test]
+    fn abs_and_sign() {
+        assert_eq!(eval_str("abs -2.5"), num(2.5));
+        assert_eq!(eval_str("sign -2.5"), num(-1.0));
+        assert_eq!(eval_str("sign 7"), num(1.0));
+        assert_eq!(eval_str("sign 0"), num(0.0));
+        assert_eq!(eval_str("sign -0"), num(0.0));
+        assert_eq!(eval_str("abs \"x\""), Value::Null);
+    }
+
+    #[test]
+    fn pi_and_e() {
+        assert_eq!(eval_str("pi"), num(std::f64::consts::PI));
+        assert_eq!(eval_str("__e"), num(std::f64::consts::E));
+    }
+
+    #[test]
+    fn assert_stops_evaluation() {
+        let (val, out) = run(&Interpreter::new(), "do __assert = 1 1 \"fine\" ; __assert = 1 2 \"one is not two\" ; print \"after\"");
+        assert!(matches!(&val, Err(Error::AssertFailed(msg)) if msg == "one is not two"), "{:?}", val);
+        assert_eq!(out, "");
+        assert_eq!(eval_str("__assert true \"unused\""), Value::Null);
+    }
+
+    #[test]
+    fn deep_recursion_fits_on_the_stack() {
+        // `eval` is on the stack once for every level of recursion in the program, so it must keep a small frame.
+        // This runs with the 8 MiB stack that a main thread typically gets.
+        let interp = load("fn count n is\n\tif = 0 n\n\t\t0\n\t+ 1 count - n 1\n");
+        let val = std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(move || interp.eval("count 1000").unwrap())
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(val, num(1000.0));
+    }
+
+    #[test]
+    fn functions_are_kept_in_a_deterministic_order() {
+        let mut a = Interpreter::new();
+        a.set_prelude(None).unwrap();
+        let mut b = Interpreter::new();
+        b.set_prelude(None).unwrap();
+        a.load("fn zeta is\n\t1\nfn alpha is\n\t2\nfn mid is\n\t3\n").unwrap();
+        b.load("fn mid is\n\t3\nfn alpha is\n\t2\nfn zeta is\n\t1\n").unwrap();
+        let names = |interp: &Interpreter| interp.funcs.keys().cloned().collect::<Vec<_>>();
+        assert_eq!(names(&a), vec!["alpha", "mid", "zeta"]);
+        assert_eq!(names(&a), names(&b));
+    }
+
+    #[test]
+    fn the_prelude_can_be_replaced_or_disabled() {
+        let mut interp = Interpreter::new();
+        interp.set_prelude(None).unwrap();
+        assert!(interp.eval("+ 1 2").is_err());
+        assert_eq!(interp.eval("__add 1 2").unwrap(), num(3.0));
+
+        interp.set_prelude(Some("fn add x y is\n\t__add x y\n")).unwrap();
+        interp.load("fn main is\n\tadd 1 2\n").unwrap();
+        assert_eq!(interp.run().unwrap(), num(3.0));
+        interp.reset().unwrap();
+        assert!(interp.funcs.contains_key("add"));
+        assert!(!interp.funcs.contains_key("main"));
+
+        // A prelude that fails to parse is reported rather than panicking
+        assert!(matches!(interp.set_prelude(Some("fn add x y is\n\t__add x\n")), Err(Error::ExpectedToken)));
+        assert!(!interp.funcs.contains_key("add"));
+    }
+
+    #[test]
+    fn chars_and_from_chars() {
+        assert_eq!(eval_str("chars \"hé\""), Value::List(vec![string("h"), string("é")]));
+        assert_eq!(eval_str("from_chars chars \"hé\""), string("hé"));
+        assert_eq!(eval_str("from_chars pair \"ab\" \"c\""), string("abc"));
+        assert_eq!(eval_str("from_chars pair \"a\" 1"), Value::Null);
+        assert_eq!(eval_str("chars 1"), Value::Null);
+    }
+
+    #[test]
+    fn to_num_parses_finite_numbers() {
+        assert_eq!(eval_str("to_num \" -4.5 \""), num(-4.5));
+        assert_eq!(eval_str("to_num 3"), num(3.0));
+        for s in ["", "abc", "1 2", "inf", "-infinity", "NaN", "1e400"].iter() {
+            assert_eq!(eval_str(&format!("to_num \"{}\"", s)), Value::Null, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn upper_and_lower() {
+        assert_eq!(eval_str("upper \"aBß\""), string("ABSS"));
+        assert_eq!(eval_str("lower \"AbC\""), string("abc"));
+        assert_eq!(eval_str("upper null"), Value::Null);
+    }
+
+    #[test]
+    fn starts_with_and_contains() {
+        assert_eq!(eval_str("starts_with \"hello\" \"he\""), Value::Bool(true));
+        assert_eq!(eval_str("starts_with \"hello\" \"lo\""), Value::Bool(false));
+        assert_eq!(eval_str("contains \"hello\" \"ll\""), Value::Bool(true));
+        assert_eq!(eval_str("contains \"hello\" \"\""), Value::Bool(true));
+        assert_eq!(eval_str("contains pair 1 2 1"), Value::Null);
+    }
+
+    #[test]
+    fn trim_removes_surrounding_whitespace() {
+        assert_eq!(eval_str("trim \" \ta b\n\""), string("a b"));
+        assert_eq!(eval_str("trim 1"), Value::Null);
+    }
+
+    #[test]
+    fn replace_substitutes_every_occurrence() {
+        assert_eq!(eval_str("replace \"a-b-c\" \"-\" \"+\""), string("a+b+c"));
+        assert_eq!(eval_str("replace \"aaa\" \"aa\" \"b\""), string("ba"));
+        assert_eq!(eval_str("replace \"abc\" \"\" \"x\""), Value::Null);
+        assert_eq!(eval_str("replace \"abc\" \"b\" 1"), Value::Null);
+    }
+
+    #[test]
+    fn bitwise_operations_on_integers() {
+        assert_eq!(eval_str("band 12 10"), num(8.0));
+        assert_eq!(eval_str("bor 12 10"), num(14.0));
+        assert_eq!(eval_str("bxor 12 10"), num(6.0));
+        assert_eq!(eval_str("bnot 0"), num(-1.0));
+        assert_eq!(eval_str("shl 1 4"), num(16.0));
+        assert_eq!(eval_str("shr -16 2"), num(-4.0));
+        assert_eq!(eval_str("band 7.9 3.2"), num(3.0));
+        assert_eq!(eval_str("shl 1 64"), Value::Null);
+        assert_eq!(eval_str("shr 1 -1"), Value::Null);
+    }
+
+    #[test]
+    fn seeded_random_numbers_are_reproducible() {
+        let sample = |seed| {
+            let mut interp = Interpreter::new();
+            interp.set_seed(seed);
+            (0..5).map(|_| interp.eval("random").unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(sample(42), sample(42));
+        assert_ne!(sample(42), sample(43));
+        for val in sample(7) {
+            match val {
+                Value::Num(x) => assert!((0.0..1.0).contains(&x), "{}", x),
+                val => panic!("{:?}", val),
+            }
+        }
+    }
+
+    #[test]
+    fn time_counts_seconds_since_the_epoch() {
+        let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs_f64();
+        match eval_str("time") {
+            Value::Num(t) => assert!((t - now).abs() < 60.0, "{} vs {}", t, now),
+            val => panic!("{:?}", val),
+        }
+    }
+
+    #[test]
+    fn env_reads_environment_variables() {
+        env::set_var("ATTO_TEST_ENV", "value");
+        assert_eq!(eval_str("env \"ATTO_TEST_ENV\""), string("value"));
+        assert_eq!(eval_str("env \"ATTO_TEST_ENV_UNSET\""), Value::Null);
+        assert_eq!(eval_str("env 1"), Value::Null);
+    }
+
+    #[test]
+    fn write_file_writes_its_contents() {
+        let fname = temp_path("write.txt");
+        let (val, _) = run(&Interpreter::new(), &format!("write_file \"{}\" pair 1 \"a\"", fname));
+        assert_eq!(val.unwrap(), Value::Null);
+        assert_eq!(fs::read_to_string(&fname).unwrap(), "[1, a]");
+        fs::remove_file(&fname).unwrap();
+
+        // Both operands are evaluated, even when the file name isn't a string
+        let (val, out) = run(&Interpreter::new(), "write_file 5 print \"side effect\"");
+        assert_eq!((val.unwrap(), out.as_str()), (Value::Null, "side effect\n"));
+
+        let missing = temp_path("missing-dir/write.txt");
+        let (val, _) = run(&Interpreter::new(), &format!("write_file \"{}\" 1", missing));
+        assert!(matches!(&val, Err(Error::CannotWrite(f, _)) if *f == missing), "{:?}", val);
+    }
+
+    #[test]
+    fn unreadable_input_is_null() {
+        let mut reader = io::Cursor::new(b"\xff\xfe\n".to_vec());
+        assert_eq!(read_line(&mut reader), Value::Null);
+    }
+
+    #[test]
+    fn files_can_run_from_another_entry_point() {
+        let interp = load("fn main is\n\t1\nfn other is\n\t2\nfn takes x is\n\tx\n");
+        assert_eq!(interp.run_entry("other").unwrap(), num(2.0));
+        assert_eq!(interp.run_entry("main").unwrap(), num(1.0));
+        assert!(matches!(interp.run_entry("takes"), Err(Error::IncorrectArity(_, 1, 0))));
+        assert!(matches!(interp.run_entry("missing"), Err(Error::CannotFind(_))));
+    }
+
+    #[test]
+    fn parsed_code_can_be_cloned_and_compared() {
+        let interp = Interpreter::new();
+        let expr = interp.parse("if = 1 2 pair 3 4 5").unwrap();
+        assert_eq!(expr.clone(), expr);
+        assert_eq!(interp.parse("if = 1 2 (pair 3 4) 5").unwrap(), expr);
+        assert_ne!(interp.parse("if = 1 2 pair 3 4 6").unwrap(), expr);
+        let f = &interp.funcs["len"];
+        assert_eq!(f.clone(), *f);
+    }
+
+    #[test]
+    fn expressions_parse_separately_from_evaluation() {
+        let interp = load("fn inc x is\n\t+ x 1\n");
+        let expr = interp.parse("inc __print 1").unwrap();
+        assert_eq!(expr, Expr::Call("inc".to_string(), vec![Expr::Print(Box::new(Expr::Value(num(1.0))))]));
+        // Parsing has no side effects
+        let mut out = Vec::new();
+        assert_eq!(interp.eval_expr_to(&expr, Box::new(&mut out)).unwrap(), num(2.0));
+        assert_eq!(out, b"1\n");
+        assert!(matches!(interp.parse("inc 1 2"), Err(Error::Unexpected(_))));
+        assert!(matches!(interp.parse("inc"), Err(Error::IncorrectArity(..))));
+    }
+
+    #[test]
+    fn sequences_evaluate_in_order() {
+        let interp = load("fn greet x is\n\tdo print \"hello\"\n\t; print x\n\t; 3\n");
+        let (val, out) = run(&interp, "do print 1 ; greet \"world\" ; print 2");
+        assert_eq!((val.unwrap(), out.as_str()), (num(2.0), "1\nhello\nworld\n2\n"));
+        assert_eq!(run(&interp, "greet 0").0.unwrap(), num(3.0));
+        assert_eq!(eval_str("+ do 1 ; 2 3"), num(5.0));
+    }
+
+    #[test]
+    fn keywords_are_spelled_once() {
+        for (name, token) in KEYWORDS.iter() {
+            assert_eq!(lex(name), vec![token.clone()]);
+            assert_eq!(token.to_string(), *name);
+            assert_eq!(KEYWORDS.iter().filter(|(_, t)| t == token).count(), 1, "{}", name);
+        }
+    }
+
+    #[test]
+    fn fuel_bounds_evaluation() {
+        let mut interp = load("fn spin n is\n\tif = n 0\n\t\t0\n\tspin - n 1\n");
+        interp.set_fuel(Some(100));
+        assert!(matches!(interp.eval("spin 1000"), Err(Error::OutOfFuel)));
+        // Each evaluation gets its own supply of fuel
+        assert_eq!(interp.eval("spin 5").unwrap(), num(0.0));
+        assert_eq!(interp.eval("spin 5").unwrap(), num(0.0));
+        interp.set_fuel(Some(1));
+        assert_eq!(interp.eval("7").unwrap(), num(7.0));
+        assert!(matches!(interp.eval("__add 1 2"), Err(Error::OutOfFuel)));
+        interp.set_fuel(None);
+        assert_eq!(interp.eval("spin 100").unwrap(), num(0.0));
+    }
+
+    #[test]
+    fn the_tail_of_an_empty_list_is_empty() {
+        assert_eq!(eval_str("tail empty"), Value::List(vec![]));
+        assert_eq!(eval_str("tail wrap 1"), Value::List(vec![]));
+        assert_eq!(eval_str("tail \"a\""), Value::Null);
+        // Recursions in core stop at the empty list rather than relying on its tail being null
+        let list = |xs: &[f64]| Value::List(xs.iter().cloned().map(num).collect());
+        assert_eq!(eval_str("skip 1 pair 1 2"), list(&[2.0]));
+        assert_eq!(eval_str("skip 5 pair 1 2"), list(&[]));
+        assert_eq!(eval_str("skip 1.5 pair 1 2"), list(&[]));
+        assert_eq!(eval_str("len empty"), num(0.0));
+        assert_eq!(eval_str("in 3 pair 1 2"), Value::Bool(false));
+    }
+
+    #[test]
+    fn head_and_tail_of_scalars_and_strings() {
+        assert_eq!(eval_str("__head 5"), num(5.0));
+        assert_eq!(eval_str("__tail 5"), Value::Null);
+        assert_eq!(eval_str("__head null"), Value::Null);
+        assert_eq!(eval_str("__tail true"), Value::Null);
+        // Strings are split by character, not by byte
+        assert_eq!(eval_str("__head \"éa\""), string("é"));
+        assert_eq!(eval_str("__tail \"éa\""), string("a"));
+        assert_eq!(eval_str("__tail \"é\""), Value::Null);
+        assert_eq!(eval_str("__head \"\""), Value::Null);
     }
 }